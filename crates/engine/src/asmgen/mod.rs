@@ -0,0 +1,384 @@
+use std::fmt::Write as _;
+
+use crate::codegen::{Eof, Options, OutputBuffering, TapeBounds, uses_sync, wrap_to_cell};
+use crate::lexer::Span;
+use crate::parser::{Node, NodeKind};
+
+mod prelude;
+
+// x86-64 System V backend emitting GNU assembler (AT&T syntax) text. Register roles match the
+// JIT: r12 = address of the current cell, r13 = tape base, r14 = the thread's State. All three
+// are callee-saved, so libc and helper calls leave them intact. Runtime helpers live in the
+// prelude and call the same libc/pthread entry points as the IR backend, so the output needs
+// nothing beyond binutils and the C library to become an executable.
+
+pub fn generate_asm(nodes: &[Node], opts: &Options) -> String {
+    let mut g = AsmGen {
+        out: String::with_capacity(64 * 1024),
+        uniq: 0,
+        deferred: Vec::new(),
+        output: opts.output,
+        tape_len: opts.tape_len,
+        cell_bits: opts.cell_bits,
+        bounds: opts.bounds,
+        eof: opts.eof,
+        sync: uses_sync(nodes),
+    };
+    prelude::emit_prelude(&mut g);
+    let main = g.with_temp_buffer(|g| {
+        g.line(".globl main");
+        g.func("main");
+        g.prologue();
+        g.inst("call bf_init");
+        g.inst("xorl %edi, %edi");
+        g.inst("call bf_new_state");
+        g.enter_state();
+        g.emit_nodes(nodes);
+        g.epilogue();
+    });
+    for def in std::mem::take(&mut g.deferred) {
+        g.out.push_str(&def);
+    }
+    g.out.push_str(&main);
+    g.line(".section .note.GNU-stack,\"\",@progbits");
+    g.out
+}
+
+pub struct AsmGen {
+    out: String,
+    uniq: usize,
+    deferred: Vec<String>, // Thread function definitions, emitted before `main`
+    pub output: OutputBuffering,
+    pub tape_len: i64,
+    pub cell_bits: u32,
+    pub bounds: TapeBounds,
+    pub eof: Eof,
+    pub sync: bool, // Whether the program locks or signals, so needs the sync slabs and helpers
+}
+
+impl AsmGen {
+    fn line(&mut self, s: &str) {
+        let _ = writeln!(self.out, "{s}");
+    }
+
+    fn inst(&mut self, s: &str) {
+        let _ = writeln!(self.out, "    {s}");
+    }
+
+    fn label(&mut self, name: &str) {
+        let _ = writeln!(self.out, "{name}:");
+    }
+
+    /// Start a function: aligned, typed for the symbol table, then its label
+    fn func(&mut self, name: &str) {
+        self.line("");
+        self.line(".p2align 4");
+        self.line(&format!(".type {name}, @function"));
+        self.label(name);
+    }
+
+    fn with_temp_buffer<F: FnOnce(&mut Self)>(&mut self, f: F) -> String {
+        let saved_out = std::mem::take(&mut self.out);
+        f(self);
+        std::mem::replace(&mut self.out, saved_out)
+    }
+
+    fn fresh_label(&mut self, kind: &str) -> String {
+        self.uniq += 1;
+        format!(".L{kind}{}", self.uniq)
+    }
+
+    /// Save callee-saved registers. Five pushes after the return address keep rsp 16-byte
+    /// aligned for calls.
+    fn prologue(&mut self) {
+        for reg in ["rbx", "r12", "r13", "r14", "r15"] {
+            self.inst(&format!("pushq %{reg}"));
+        }
+    }
+
+    fn epilogue(&mut self) {
+        self.inst("xorl %eax, %eax");
+        for reg in ["r15", "r14", "r13", "r12", "rbx"] {
+            self.inst(&format!("popq %{reg}"));
+        }
+        self.inst("ret");
+    }
+
+    /// Bytes per cell
+    fn cell_size(&self) -> i64 {
+        i64::from(self.cell_bits / 8)
+    }
+
+    /// Operand-size suffix of cell-wide instructions
+    fn suffix(&self) -> char {
+        match self.cell_bits {
+            8 => 'b',
+            16 => 'w',
+            32 => 'l',
+            _ => 'q',
+        }
+    }
+
+    /// `reg` ("a", "c", ...) narrowed to the cell width
+    fn cell_reg(&self, reg: char) -> String {
+        match self.cell_bits {
+            8 => format!("%{reg}l"),
+            16 => format!("%{reg}x"),
+            32 => format!("%e{reg}x"),
+            _ => format!("%r{reg}x"),
+        }
+    }
+
+    /// `op $imm, dst` for a cell-wide `op`, going through rax when a 64-bit immediate does not
+    /// fit in the sign-extended 32 bits x86 encodes
+    fn cell_imm(&mut self, op: &str, imm: i64, dst: &str) {
+        let sfx = self.suffix();
+        if i32::try_from(imm).is_ok() {
+            self.inst(&format!("{op}{sfx} ${imm}, {dst}"));
+        } else {
+            self.inst(&format!("movabsq ${imm}, %rax"));
+            self.inst(&format!("{op}{sfx} %rax, {dst}"));
+        }
+    }
+
+    /// Take the State in rax as this thread's, and point r12 at its start cell
+    fn enter_state(&mut self) {
+        self.inst("movq %rax, %r14");
+        self.inst("leaq tape(%rip), %r13");
+        self.inst("movq (%r14), %r12");
+        let scale = self.cell_size();
+        self.inst(&format!("leaq (%r13,%r12,{scale}), %r12"));
+    }
+
+    /// Define a thread entry point running `nodes` on the State passed as its argument
+    fn defer_thread(&mut self, name: &str, nodes: &[Node]) {
+        let def = self.with_temp_buffer(|g| {
+            g.func(name);
+            g.prologue();
+            if g.bounds == TapeBounds::Checked {
+                // Number this thread for bounds reports; main is 0
+                g.inst("movq %rdi, %rbx");
+                g.inst("movl $1, %eax");
+                g.inst("lock xaddq %rax, bf_thread_count(%rip)");
+                g.inst("addq $1, %rax");
+                g.inst("movq %rax, %fs:bf_thread_id@tpoff");
+                g.inst("movq %rbx, %rdi");
+            }
+            g.inst("movq %rdi, %rax");
+            g.enter_state();
+            g.emit_nodes(nodes);
+            g.epilogue();
+        });
+        self.deferred.push(def);
+    }
+
+    /// rdi = tape index of the current cell
+    fn arg_cell_index(&mut self) {
+        self.inst("movq %r12, %rdi");
+        self.inst("subq %r13, %rdi");
+        if self.cell_bits > 8 {
+            let shift = self.cell_size().trailing_zeros();
+            self.inst(&format!("shrq ${shift}, %rdi"));
+        }
+    }
+
+    /// Move the pointer by `cells` cells, wrapped or checked per the bounds mode
+    fn move_ptr(&mut self, cells: i64, span: Span) {
+        let cells = match self.bounds {
+            TapeBounds::Wrap => cells.rem_euclid(self.tape_len),
+            _ => cells,
+        };
+        let delta = cells.wrapping_mul(self.cell_size());
+        match i32::try_from(delta) {
+            Ok(d) => self.inst(&format!("addq ${d}, %r12")),
+            Err(_) => {
+                self.inst(&format!("movabsq ${delta}, %rax"));
+                self.inst("addq %rax, %r12");
+            }
+        }
+        self.bound_addr("%r12", span);
+    }
+
+    /// Bring the cell address in `reg` back onto the tape (`--tape-wrap`, where offsets are
+    /// reduced so at most one lap is over) or fail with a report (`--bounds-check`). Uses rsi
+    /// and rdi as scratch so rax can carry a value across.
+    fn bound_addr(&mut self, reg: &str, span: Span) {
+        if self.bounds == TapeBounds::Unchecked {
+            return;
+        }
+        let bytes = self.tape_len * self.cell_size();
+        let ok = self.fresh_label("in_bounds");
+        self.inst(&format!("movq {reg}, %rsi"));
+        self.inst("subq %r13, %rsi");
+        self.inst(&format!("movabsq ${bytes}, %rdi"));
+        self.inst("cmpq %rdi, %rsi");
+        // Unsigned, so addresses below the tape compare as huge
+        self.inst(&format!("jb {ok}"));
+        if self.bounds == TapeBounds::Wrap {
+            self.inst(&format!("subq %rdi, {reg}"));
+        } else {
+            self.inst("movq %rsi, %rdi");
+            if self.cell_bits > 8 {
+                let shift = self.cell_size().trailing_zeros();
+                self.inst(&format!("sarq ${shift}, %rdi"));
+            }
+            self.inst(&format!("movl ${}, %esi", span.line));
+            self.inst(&format!("movl ${}, %edx", span.col));
+            self.inst("call bf_bounds_fail");
+        }
+        self.label(&ok);
+    }
+
+    fn emit_nodes(&mut self, nodes: &[Node]) {
+        for n in nodes {
+            self.emit_node(n);
+        }
+    }
+
+    fn emit_node(&mut self, n: &Node) {
+        match &n.kind {
+            NodeKind::IncPtr => self.move_ptr(1, n.span),
+            NodeKind::DecPtr => self.move_ptr(-1, n.span),
+            NodeKind::Move(d) => self.move_ptr(*d, n.span),
+            NodeKind::IncCell => self.cell_imm("add", 1, "(%r12)"),
+            NodeKind::DecCell => self.cell_imm("sub", 1, "(%r12)"),
+            NodeKind::Add(d) => {
+                let d = wrap_to_cell(*d, self.cell_bits); // Cells wrap at their width
+                self.cell_imm("add", d, "(%r12)");
+            }
+            NodeKind::Clear => self.cell_imm("mov", 0, "(%r12)"),
+            NodeKind::MulAdd(targets) => self.emit_mul_add(targets, n.span),
+            NodeKind::Scan(step) => {
+                let head = self.fresh_label("scan");
+                let done = self.fresh_label("scan_end");
+                self.label(&head);
+                self.cell_imm("cmp", 0, "(%r12)");
+                self.inst(&format!("je {done}"));
+                self.move_ptr(*step, n.span);
+                self.inst(&format!("jmp {head}"));
+                self.label(&done);
+            }
+            NodeKind::Output => {
+                // The low byte comes first in a little-endian wide cell
+                self.inst("movzbl (%r12), %edi");
+                self.inst("call bf_output");
+            }
+            NodeKind::Input => {
+                self.inst("movq %r12, %rdi");
+                self.inst("call bf_input");
+            }
+            NodeKind::LockAcquire => {
+                self.arg_cell_index();
+                self.inst("movq %rdi, %rsi");
+                self.inst("movq %r14, %rdi");
+                self.inst("call bf_lock_acquire");
+            }
+            NodeKind::LockRelease => {
+                self.inst("movq %r14, %rdi");
+                self.inst("call bf_lock_release");
+            }
+            NodeKind::Wait => {
+                self.arg_cell_index();
+                self.inst("call bf_wait");
+            }
+            NodeKind::Notify => {
+                self.arg_cell_index();
+                self.inst("call bf_notify");
+            }
+            NodeKind::Sleep(t) => {
+                self.inst(&format!("movl ${}, %edi", *t as u32));
+                self.inst("call bf_sleep");
+            }
+            NodeKind::Loop(body) => {
+                let head = self.fresh_label("loop");
+                let done = self.fresh_label("loop_end");
+                self.label(&head);
+                self.cell_imm("cmp", 0, "(%r12)");
+                self.inst(&format!("je {done}"));
+                self.emit_nodes(body);
+                self.inst(&format!("jmp {head}"));
+                self.label(&done);
+            }
+            NodeKind::Parallel(branches) => self.emit_parallel(branches),
+        }
+    }
+
+    /// Add `factor * current` to each target cell, then clear the current cell. Only the low
+    /// `cell_bits` of each product matter.
+    fn emit_mul_add(&mut self, targets: &[(i64, i64)], span: Span) {
+        match self.cell_bits {
+            8 => self.inst("movzbl (%r12), %eax"),
+            16 => self.inst("movzwl (%r12), %eax"),
+            32 => self.inst("movl (%r12), %eax"),
+            _ => self.inst("movq (%r12), %rax"),
+        }
+        // Skipped on a zero cell, see `NodeKind::MulAdd`
+        let done = self.fresh_label("mul_add_end");
+        self.inst("testq %rax, %rax");
+        self.inst(&format!("je {done}"));
+        let sfx = self.suffix();
+        let rc = self.cell_reg('c');
+        for (offset, factor) in targets {
+            let factor = wrap_to_cell(*factor, self.cell_bits);
+            if self.cell_bits < 64 {
+                self.inst(&format!("imull ${factor}, %eax, %ecx"));
+            } else if i32::try_from(factor).is_ok() {
+                self.inst(&format!("imulq ${factor}, %rax, %rcx"));
+            } else {
+                self.inst(&format!("movabsq ${factor}, %rcx"));
+                self.inst("imulq %rax, %rcx");
+            }
+            if self.bounds == TapeBounds::Unchecked {
+                let disp = offset * self.cell_size();
+                self.inst(&format!("add{sfx} {rc}, {disp}(%r12)"));
+            } else {
+                let offset = match self.bounds {
+                    TapeBounds::Wrap => offset.rem_euclid(self.tape_len),
+                    _ => *offset,
+                };
+                let disp = offset * self.cell_size();
+                self.inst(&format!("leaq {disp}(%r12), %rdx"));
+                self.bound_addr("%rdx", span);
+                self.inst(&format!("add{sfx} {rc}, (%rdx)"));
+            }
+        }
+        self.cell_imm("mov", 0, "(%r12)");
+        self.label(&done);
+    }
+
+    /// Start one thread per branch, each with its own State starting at the current cell, then
+    /// join them all. The pthread_t handles live in a stack area rounded to keep rsp aligned.
+    fn emit_parallel(&mut self, branches: &[Vec<Node>]) {
+        let pid = self.uniq;
+        self.uniq += 1;
+        // Thread handles, then the child States
+        let k = branches.len();
+        let frame = (2 * k * 8).next_multiple_of(16);
+        self.inst(&format!("subq ${frame}, %rsp"));
+        for (i, b) in branches.iter().enumerate() {
+            let name = format!("thread_p{pid}_{i}");
+            self.defer_thread(&name, b);
+            self.arg_cell_index();
+            self.inst("call bf_new_state");
+            self.inst(&format!("movq %rax, {}(%rsp)", (k + i) * 8));
+            self.inst(&format!("leaq {}(%rsp), %rdi", i * 8));
+            self.inst("xorl %esi, %esi");
+            self.inst(&format!("leaq {name}(%rip), %rdx"));
+            self.inst("movq %rax, %rcx");
+            self.inst("call pthread_create@PLT");
+        }
+        for i in 0..k {
+            self.inst(&format!("movq {}(%rsp), %rdi", i * 8));
+            self.inst("xorl %esi, %esi");
+            self.inst("call pthread_join@PLT");
+            self.inst(&format!("movq {}(%rsp), %rdi", (k + i) * 8));
+            self.inst("call bf_free_state");
+        }
+        self.inst(&format!("addq ${frame}, %rsp"));
+        // Flush at the join, see `OutputBuffering`
+        if self.output != OutputBuffering::None {
+            self.inst("xorl %edi, %edi");
+            self.inst("call fflush@PLT");
+        }
+    }
+}
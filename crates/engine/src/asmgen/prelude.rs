@@ -0,0 +1,272 @@
+use super::AsmGen;
+use crate::codegen::{
+    Eof, LOCK_STACK_INIT, MUTEX_STRIDE, OUT_BUF_LEN, OutputBuffering, TapeBounds, bounds_message,
+};
+
+// glibc values of the `setvbuf` mode constants
+const IOFBF: i32 = 0;
+const IOLBF: i32 = 1;
+
+/// Shared tape and sync slabs, and the assembly counterparts of the IR runtime helpers. A
+/// State is `{ ptr, stack, sp, cap }`: a thread's start position and its lock stack.
+///
+/// The slabs are left zeroed rather than initialized at startup: glibc's static mutex and
+/// condvar initializers are all zero bytes, so every slot is ready as is, and the kernel only
+/// backs the pages of cells a program actually locks or signals. Programs without sync ops
+/// get no slabs at all. The IR backend allocates chunks on first touch instead
+/// (`codegen::chunks`) because its tape may grow; this backend rejects `--tape-grow`, so its
+/// slabs can be sized up front and demand paging gives the same lazy cost without the
+/// directory lookups.
+pub fn emit_prelude(g: &mut AsmGen) {
+    let tape_len = g.tape_len;
+    let tape_bytes = tape_len * i64::from(g.cell_bits / 8);
+    g.line(".bss");
+    g.line(".p2align 6");
+    g.label("tape");
+    g.inst(&format!(".zero {tape_bytes}"));
+    if g.sync {
+        for name in ["locks", "conds", "cond_mtx"] {
+            g.label(name);
+            g.inst(&format!(".zero {}", tape_len * MUTEX_STRIDE));
+        }
+    }
+    if g.bounds == TapeBounds::Checked {
+        // Thread numbers for `bounds_message`
+        g.label("bf_thread_count");
+        g.inst(".zero 8");
+        g.line(".section .tbss,\"awT\",@nobits");
+        g.line(".p2align 3");
+        g.label("bf_thread_id");
+        g.inst(".zero 8");
+        g.line(".section .rodata");
+        g.label("bf_bounds_msg");
+        let msg = bounds_message("%ld", "%ld", g.bounds, tape_len, ("%ld", "%ld"));
+        g.inst(&format!(".asciz \"{msg}\\n\""));
+    }
+    g.line("");
+    g.line(".text");
+
+    if g.bounds == TapeBounds::Checked {
+        // bf_bounds_fail(idx, line, col): flush, report on stderr and exit(1)
+        g.func("bf_bounds_fail");
+        g.inst("pushq %rdi");
+        g.inst("pushq %rsi");
+        g.inst("pushq %rdx");
+        if g.output != OutputBuffering::None {
+            g.inst("xorl %edi, %edi");
+            g.inst("call fflush@PLT");
+        }
+        g.inst("popq %r9");
+        g.inst("popq %r8");
+        g.inst("popq %rcx");
+        g.inst("subq $8, %rsp");
+        g.inst("movl $2, %edi");
+        g.inst("leaq bf_bounds_msg(%rip), %rsi");
+        g.inst("movq %fs:bf_thread_id@tpoff, %rdx");
+        g.inst("xorl %eax, %eax");
+        g.inst("call dprintf@PLT");
+        g.inst("movl $1, %edi");
+        g.inst("call exit@PLT");
+    }
+
+    // bf_new_state(ptr) -> State*
+    g.func("bf_new_state");
+    g.inst("pushq %rbx");
+    g.inst("pushq %r12");
+    g.inst("subq $8, %rsp");
+    g.inst("movq %rdi, %r12");
+    g.inst("movl $32, %edi");
+    g.inst("call malloc@PLT");
+    g.inst("movq %rax, %rbx");
+    g.inst("movq %r12, 0(%rbx)");
+    g.inst(&format!("movl ${}, %edi", LOCK_STACK_INIT * 8));
+    g.inst("call malloc@PLT");
+    g.inst("movq %rax, 8(%rbx)");
+    g.inst("movq $0, 16(%rbx)");
+    g.inst(&format!("movq ${LOCK_STACK_INIT}, 24(%rbx)"));
+    g.inst("movq %rbx, %rax");
+    g.inst("addq $8, %rsp");
+    g.inst("popq %r12");
+    g.inst("popq %rbx");
+    g.inst("ret");
+
+    // bf_free_state(S): free the lock stack, then the State
+    g.func("bf_free_state");
+    g.inst("pushq %rbx");
+    g.inst("movq %rdi, %rbx");
+    g.inst("movq 8(%rbx), %rdi");
+    g.inst("call free@PLT");
+    g.inst("movq %rbx, %rdi");
+    g.inst("call free@PLT");
+    g.inst("popq %rbx");
+    g.inst("ret");
+
+    if g.sync {
+        emit_sync_helpers(g);
+    }
+
+    // bf_sleep(ticks): 0.1s per tick
+    g.func("bf_sleep");
+    g.inst("subq $24, %rsp");
+    g.inst("movl %edi, %eax");
+    g.inst("imulq $100000000, %rax, %rax");
+    g.inst("xorl %edx, %edx");
+    g.inst("movl $1000000000, %ecx");
+    g.inst("divq %rcx");
+    g.inst("movq %rax, 0(%rsp)");
+    g.inst("movq %rdx, 8(%rsp)");
+    g.inst("movq %rsp, %rdi");
+    g.inst("xorl %esi, %esi");
+    g.inst("call nanosleep@PLT");
+    g.inst("addq $24, %rsp");
+    g.inst("ret");
+
+    // bf_output(v)
+    g.func("bf_output");
+    g.inst("subq $8, %rsp");
+    g.inst("movzbl %dil, %edi");
+    g.inst("call putchar@PLT");
+    if g.output == OutputBuffering::None {
+        g.inst("xorl %edi, %edi");
+        g.inst("call fflush@PLT");
+    }
+    g.inst("addq $8, %rsp");
+    g.inst("ret");
+
+    // bf_input(p): store the next byte, zero-extended to the cell width, or the --eof value at
+    // end of input
+    g.func("bf_input");
+    g.inst("pushq %rbx");
+    g.inst("movq %rdi, %rbx");
+    if g.output != OutputBuffering::None {
+        // Flush before input, see `OutputBuffering`
+        g.inst("xorl %edi, %edi");
+        g.inst("call fflush@PLT");
+    }
+    g.inst("call getchar@PLT");
+    if g.cell_bits == 64 {
+        // getchar returns an int, leaving the upper half of rax undefined. Sign extension
+        // keeps EOF's -1 all ones.
+        g.inst("movslq %eax, %rax");
+    }
+    let (sfx, reg) = (g.suffix(), g.cell_reg('a'));
+    match g.eof {
+        Eof::Unchanged => {
+            g.inst("testl %eax, %eax");
+            g.inst("js 1f");
+            g.inst(&format!("mov{sfx} {reg}, (%rbx)"));
+            g.label("1");
+        }
+        Eof::Zero => {
+            g.inst("testl %eax, %eax");
+            g.inst("jns 1f");
+            g.inst("xorl %eax, %eax");
+            g.label("1");
+            g.inst(&format!("mov{sfx} {reg}, (%rbx)"));
+        }
+        Eof::MinusOne => {
+            // getchar's EOF is -1, all ones at any width
+            g.inst(&format!("mov{sfx} {reg}, (%rbx)"));
+        }
+    }
+    g.inst("popq %rbx");
+    g.inst("ret");
+
+    // bf_init(): stdout buffering
+    g.func("bf_init");
+    g.inst("subq $8, %rsp");
+    let mode = match g.output {
+        OutputBuffering::None => None,
+        OutputBuffering::Line => Some(IOLBF),
+        OutputBuffering::Full => Some(IOFBF),
+    };
+    if let Some(mode) = mode {
+        g.inst("movq stdout@GOTPCREL(%rip), %rax");
+        g.inst("movq (%rax), %rdi");
+        g.inst("xorl %esi, %esi");
+        g.inst(&format!("movl ${mode}, %edx"));
+        g.inst(&format!("movl ${OUT_BUF_LEN}, %ecx"));
+        g.inst("call setvbuf@PLT");
+    }
+    g.inst("addq $8, %rsp");
+    g.inst("ret");
+    g.line("");
+}
+
+/// Lock, wait and notify helpers over the sync slabs
+fn emit_sync_helpers(g: &mut AsmGen) {
+    // bf_lock_acquire(S, idx): lock, then push idx, doubling the stack when it is full
+    g.func("bf_lock_acquire");
+    g.inst("pushq %rbx");
+    g.inst("pushq %r12");
+    g.inst("pushq %r13");
+    g.inst("movq %rdi, %rbx");
+    g.inst("movq %rsi, %r12");
+    slot(g, "locks", "%rsi", "%rdi");
+    g.inst("call pthread_mutex_lock@PLT");
+    g.inst("movq 16(%rbx), %rax");
+    g.inst("cmpq 24(%rbx), %rax");
+    g.inst("jne 1f");
+    g.inst("movq 24(%rbx), %rdi");
+    g.inst("shlq $4, %rdi");
+    g.inst("call malloc@PLT");
+    g.inst("movq %rax, %r13");
+    g.inst("movq %rax, %rdi");
+    g.inst("movq 8(%rbx), %rsi");
+    g.inst("movq 24(%rbx), %rdx");
+    g.inst("shlq $3, %rdx");
+    g.inst("call memcpy@PLT");
+    g.inst("movq 8(%rbx), %rdi");
+    g.inst("call free@PLT");
+    g.inst("movq %r13, 8(%rbx)");
+    g.inst("shlq $1, 24(%rbx)");
+    g.label("1");
+    g.inst("movq 16(%rbx), %rax");
+    g.inst("movq 8(%rbx), %rcx");
+    g.inst("movq %r12, (%rcx,%rax,8)");
+    g.inst("addq $1, 16(%rbx)");
+    g.inst("popq %r13");
+    g.inst("popq %r12");
+    g.inst("popq %rbx");
+    g.inst("ret");
+
+    // bf_lock_release(S): pop, then unlock
+    g.func("bf_lock_release");
+    g.inst("subq $8, %rsp");
+    g.inst("movq 16(%rdi), %rax");
+    g.inst("subq $1, %rax");
+    g.inst("movq %rax, 16(%rdi)");
+    g.inst("movq 8(%rdi), %rcx");
+    g.inst("movq (%rcx,%rax,8), %rsi");
+    slot(g, "locks", "%rsi", "%rdi");
+    g.inst("call pthread_mutex_unlock@PLT");
+    g.inst("addq $8, %rsp");
+    g.inst("ret");
+
+    // bf_wait(idx) / bf_notify(idx): cond_wait or broadcast under the cell's condvar mutex
+    for (name, op) in [
+        ("bf_wait", "pthread_cond_wait"),
+        ("bf_notify", "pthread_cond_broadcast"),
+    ] {
+        g.func(name);
+        g.inst("pushq %rbx");
+        g.inst("movq %rdi, %rbx");
+        slot(g, "cond_mtx", "%rbx", "%rdi");
+        g.inst("call pthread_mutex_lock@PLT");
+        slot(g, "conds", "%rbx", "%rdi");
+        slot(g, "cond_mtx", "%rbx", "%rsi");
+        g.inst(&format!("call {op}@PLT"));
+        slot(g, "cond_mtx", "%rbx", "%rdi");
+        g.inst("call pthread_mutex_unlock@PLT");
+        g.inst("popq %rbx");
+        g.inst("ret");
+    }
+}
+
+/// `dst = &slab[idx]` for a slab with `MUTEX_STRIDE`-byte slots (clobbers %rax)
+fn slot(g: &mut AsmGen, slab: &str, idx: &str, dst: &str) {
+    g.inst(&format!("movq {idx}, {dst}"));
+    g.inst(&format!("shlq ${}, {dst}", MUTEX_STRIDE.trailing_zeros()));
+    g.inst(&format!("leaq {slab}(%rip), %rax"));
+    g.inst(&format!("addq %rax, {dst}"));
+}
@@ -0,0 +1,264 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output, Stdio};
+use std::{env, fs};
+
+/// Libraries the generated code and the Rust runtime link against
+const SYSTEM_LIBS: &[&str] = &["-lpthread", "-ldl", "-lm"];
+
+/// First LLVM release that accepts opaque-pointer IR without extra flags
+const OPAQUE_POINTERS_SINCE: u32 = 15;
+
+pub struct BuildOptions<'a> {
+    pub output: &'a Path,
+    pub sanitize: bool,            // Link the race-detecting runtime
+    pub runtime: Option<&'a Path>, // `--runtime`, or searched for when `None`
+    pub opt_level: u8,             // Passed on to the LLVM backend
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    NoCompiler,
+    Io(String, io::Error),
+    ToolFailed { tool: String, output: Output },
+    RuntimeMissing(Vec<PathBuf>), // Every path searched
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::NoCompiler => write!(
+                f,
+                "no LLVM compiler found: install clang, or llc plus a C compiler (override with $CLANG, $LLC, $CC)"
+            ),
+            BuildError::Io(what, err) => write!(f, "{what}: {err}"),
+            BuildError::ToolFailed { tool, output } => {
+                write!(f, "`{tool}` failed ({})", output.status)?;
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if !stderr.trim().is_empty() {
+                    write!(f, "\n{}", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+            BuildError::RuntimeMissing(searched) => {
+                write!(f, "runtime library not found; searched:")?;
+                for path in searched {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                write!(f, "\npass --runtime <libruntime.a> or set $BF_RUNTIME")
+            }
+        }
+    }
+}
+
+/// How IR is turned into an executable: clang in one step, or llc followed by the C compiler
+pub enum Toolchain {
+    Clang {
+        clang: OsString,
+        major: u32,
+    },
+    Llc {
+        llc: OsString,
+        cc: OsString,
+        major: u32,
+    },
+}
+
+impl Toolchain {
+    /// Prefer clang, fall back to llc. `$CLANG`, `$LLC` and `$CC` override the tool names.
+    pub fn detect() -> Result<Toolchain, BuildError> {
+        let clang = env::var_os("CLANG").unwrap_or_else(|| "clang".into());
+        if let Some(major) = llvm_major(&clang)? {
+            return Ok(Toolchain::Clang { clang, major });
+        }
+        let llc = env::var_os("LLC").unwrap_or_else(|| "llc".into());
+        if let Some(major) = llvm_major(&llc)? {
+            let cc = env::var_os("CC").unwrap_or_else(|| "cc".into());
+            return Ok(Toolchain::Llc { llc, cc, major });
+        }
+        Err(BuildError::NoCompiler)
+    }
+
+    /// Whether the IR must use typed pointers for this LLVM release
+    pub fn needs_typed_pointers(&self) -> bool {
+        let (Toolchain::Clang { major, .. } | Toolchain::Llc { major, .. }) = self;
+        *major < OPAQUE_POINTERS_SINCE
+    }
+
+    /// Compile `ir` and link it into `opts.output`
+    pub fn build(&self, ir: &str, opts: &BuildOptions) -> Result<(), BuildError> {
+        let runtime = if opts.sanitize {
+            Some(locate_runtime(opts.runtime)?)
+        } else {
+            None
+        };
+
+        let tmp = env::temp_dir().join(format!("bf-build-{}", process::id()));
+        let ll = tmp.with_extension("ll");
+        fs::write(&ll, ir)
+            .map_err(|err| BuildError::Io(format!("failed to write {}", ll.display()), err))?;
+
+        let result = match self {
+            Toolchain::Clang { clang, .. } => {
+                let mut cmd = Command::new(clang);
+                cmd.arg(format!("-O{}", opts.opt_level)).arg(&ll);
+                link_args(&mut cmd, runtime.as_deref(), opts.output);
+                run(cmd)
+            }
+            Toolchain::Llc { llc, cc, .. } => {
+                let obj = tmp.with_extension("o");
+                let mut cmd = Command::new(llc);
+                cmd.arg(format!("-O{}", opts.opt_level))
+                    .args(["-filetype=obj", "-relocation-model=pic", "-o"])
+                    .arg(&obj)
+                    .arg(&ll);
+                let result = run(cmd).and_then(|()| {
+                    let mut cmd = Command::new(cc);
+                    cmd.arg(&obj);
+                    link_args(&mut cmd, runtime.as_deref(), opts.output);
+                    run(cmd)
+                });
+                let _ = fs::remove_file(&obj);
+                result
+            }
+        };
+        let _ = fs::remove_file(&ll);
+        result
+    }
+}
+
+/// Compile C source from the C backend with `$CC` (default `cc`) into `opts.output`
+pub fn build_c(source: &str, opts: &BuildOptions) -> Result<(), BuildError> {
+    let c = env::temp_dir()
+        .join(format!("bf-build-{}", process::id()))
+        .with_extension("c");
+    fs::write(&c, source)
+        .map_err(|err| BuildError::Io(format!("failed to write {}", c.display()), err))?;
+
+    let mut cmd = Command::new(env::var_os("CC").unwrap_or_else(|| "cc".into()));
+    cmd.args(["-std=c11", "-pthread"])
+        .arg(format!("-O{}", opts.opt_level))
+        .arg(&c)
+        .arg("-o")
+        .arg(opts.output);
+    let result = run(cmd);
+    let _ = fs::remove_file(&c);
+    result
+}
+
+/// Assemble GNU-as source with `$AS` (default `as`), then link it against libc and pthreads
+pub fn build_asm(source: &str, opts: &BuildOptions) -> Result<(), BuildError> {
+    let base = env::temp_dir().join(format!("bf-build-{}", process::id()));
+    let asm = base.with_extension("s");
+    let obj = base.with_extension("o");
+    fs::write(&asm, source)
+        .map_err(|err| BuildError::Io(format!("failed to write {}", asm.display()), err))?;
+
+    let mut assemble = Command::new(env::var_os("AS").unwrap_or_else(|| "as".into()));
+    assemble.arg(&asm).arg("-o").arg(&obj);
+    let result = run(assemble).and_then(|()| {
+        let mut link = Command::new(env::var_os("CC").unwrap_or_else(|| "cc".into()));
+        link.arg(&obj);
+        link_args(&mut link, None, opts.output);
+        run(link)
+    });
+    let _ = fs::remove_file(&asm);
+    let _ = fs::remove_file(&obj);
+    result
+}
+
+fn link_args(cmd: &mut Command, runtime: Option<&Path>, output: &Path) {
+    if let Some(lib) = runtime {
+        cmd.arg(lib);
+    }
+    cmd.args(SYSTEM_LIBS).arg("-o").arg(output);
+}
+
+/// Run a tool to completion, turning a non-zero exit into an error carrying its stderr
+fn run(mut cmd: Command) -> Result<(), BuildError> {
+    let tool = cmd.get_program().to_string_lossy().into_owned();
+    let output = cmd
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| BuildError::Io(format!("failed to run `{tool}`"), err))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(BuildError::ToolFailed { tool, output })
+    }
+}
+
+/// Major LLVM version reported by `tool --version`, or `None` if the tool is not installed
+fn llvm_major(tool: &OsString) -> Result<Option<u32>, BuildError> {
+    let name = tool.to_string_lossy();
+    let output = match Command::new(tool).arg("--version").output() {
+        Ok(output) => output,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(BuildError::Io(format!("failed to run `{name}`"), err)),
+    };
+    let text = String::from_utf8_lossy(&output.stdout);
+    let major = text
+        .split("version ")
+        .nth(1)
+        .and_then(|v| v.split('.').next())
+        .and_then(|v| v.parse().ok());
+    match major {
+        Some(major) => Ok(Some(major)),
+        None => Err(BuildError::ToolFailed {
+            tool: format!("{name} --version"),
+            output,
+        }),
+    }
+}
+
+/// `libruntime.a` from `--runtime`, `$BF_RUNTIME`, next to the engine executable, or from the
+/// workspace the engine was built in, building it there first if needed
+fn locate_runtime(explicit: Option<&Path>) -> Result<PathBuf, BuildError> {
+    if let Some(path) = explicit
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("BF_RUNTIME").map(PathBuf::from))
+    {
+        return if path.exists() {
+            Ok(path)
+        } else {
+            Err(BuildError::RuntimeMissing(vec![path]))
+        };
+    }
+
+    let exe = env::current_exe()
+        .map_err(|err| BuildError::Io("failed to locate the engine executable".into(), err))?;
+    let dir = exe.parent().unwrap_or(Path::new("."));
+    let profile = if dir.ends_with("release") {
+        "release"
+    } else {
+        "debug"
+    };
+    let workspace = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .ancestors()
+        .nth(2)
+        .unwrap();
+    let mut searched = vec![dir.join("libruntime.a")];
+    let built = workspace.join("target").join(profile).join("libruntime.a");
+    if !searched.contains(&built) {
+        searched.push(built);
+    }
+    let found = |searched: &[PathBuf]| searched.iter().find(|lib| lib.exists()).cloned();
+    if let Some(lib) = found(&searched) {
+        return Ok(lib);
+    }
+
+    // An engine installed away from its sources has nothing to build from
+    if workspace.join("Cargo.toml").exists() {
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let mut cmd = Command::new(cargo);
+        cmd.current_dir(workspace).args(["build", "-p", "runtime"]);
+        if profile == "release" {
+            cmd.arg("--release");
+        }
+        eprintln!("Building runtime library...");
+        run(cmd)?;
+    }
+    found(&searched).ok_or(BuildError::RuntimeMissing(searched))
+}
@@ -0,0 +1,245 @@
+use std::fmt::Write as _;
+
+use crate::codegen::{Eof, Options, OutputBuffering, TapeBounds, uses_sync, wrap_to_cell};
+use crate::lexer::Span;
+use crate::parser::{Node, NodeKind};
+
+mod prelude;
+
+// Portable C11 + pthreads backend. Every thread keeps its pointer in a local `p`; the tape and
+// the directory of lazily created per-cell mutexes and condvars are globals, and a `State` only
+// carries what a thread needs from its parent (start position) and its own lock stack. Sync
+// helpers take the cell index explicitly, so `p` never has to be written back.
+
+pub fn generate_c(nodes: &[Node], opts: &Options) -> String {
+    let mut g = CGen {
+        out: String::with_capacity(32 * 1024),
+        indent: 0,
+        uniq: 0,
+        protos: Vec::new(),
+        deferred: Vec::new(),
+        output: opts.output,
+        tape_len: opts.tape_len,
+        cell_bits: opts.cell_bits,
+        bounds: opts.bounds,
+        eof: opts.eof,
+        sync: uses_sync(nodes),
+    };
+    prelude::emit_prelude(&mut g);
+    let main = g.with_temp_buffer(|g| {
+        g.line("int main(void) {");
+        g.indent += 1;
+        g.line("bf_init();");
+        g.line("State *S = bf_new_state(0);");
+        g.line("int64_t p = S->ptr;");
+        g.emit_nodes(nodes);
+        g.line("return 0;");
+        g.indent -= 1;
+        g.line("}");
+    });
+    for proto in std::mem::take(&mut g.protos) {
+        g.line(&proto);
+    }
+    g.line("");
+    for def in std::mem::take(&mut g.deferred) {
+        g.out.push_str(&def);
+    }
+    g.out.push_str(&main);
+    g.out
+}
+
+pub struct CGen {
+    out: String,
+    indent: usize,
+    uniq: usize,
+    protos: Vec<String>, // Thread function prototypes, emitted ahead of all definitions
+    deferred: Vec<String>, // Thread function definitions, emitted before `main`
+    pub output: OutputBuffering,
+    pub tape_len: i64,
+    pub cell_bits: u32,
+    pub bounds: TapeBounds,
+    pub eof: Eof,
+    pub sync: bool, // Whether the program locks or signals, so needs the sync helpers
+}
+
+impl CGen {
+    fn line(&mut self, s: &str) {
+        if !s.is_empty() {
+            for _ in 0..self.indent {
+                self.out.push_str("    ");
+            }
+        }
+        let _ = writeln!(self.out, "{s}");
+    }
+
+    fn with_temp_buffer<F: FnOnce(&mut Self)>(&mut self, f: F) -> String {
+        let saved_out = std::mem::take(&mut self.out);
+        let saved_indent = std::mem::replace(&mut self.indent, 0);
+        f(self);
+        let produced = std::mem::replace(&mut self.out, saved_out);
+        self.indent = saved_indent;
+        produced
+    }
+
+    /// Define a thread entry point running `nodes` on the State passed as its argument
+    fn defer_thread(&mut self, name: &str, nodes: &[Node]) {
+        self.protos.push(format!("static void *{name}(void *arg);"));
+        let def = self.with_temp_buffer(|g| {
+            g.line(&format!("static void *{name}(void *arg) {{"));
+            g.indent += 1;
+            g.line("State *S = arg;");
+            g.line("int64_t p = S->ptr;");
+            if g.bounds == TapeBounds::Checked {
+                g.line("bf_thread_id = bf_next_thread_id();");
+            }
+            g.emit_nodes(nodes);
+            g.line("return NULL;");
+            g.indent -= 1;
+            g.line("}");
+            g.line("");
+        });
+        self.deferred.push(def);
+    }
+
+    fn emit_nodes(&mut self, nodes: &[Node]) {
+        for n in nodes {
+            self.emit_node(n);
+        }
+    }
+
+    fn emit_node(&mut self, n: &Node) {
+        match &n.kind {
+            NodeKind::IncPtr => self.move_ptr(1, n.span),
+            NodeKind::DecPtr => self.move_ptr(-1, n.span),
+            NodeKind::Move(d) => self.move_ptr(*d, n.span),
+            NodeKind::IncCell => self.line("tape[p] += 1;"),
+            NodeKind::DecCell => self.line("tape[p] -= 1;"),
+            NodeKind::Add(d) => {
+                let d = wrap_to_cell(*d, self.cell_bits); // Cells wrap at their width
+                self.line(&format!("tape[p] {};", compound(d)));
+            }
+            NodeKind::Clear => self.line("tape[p] = 0;"),
+            NodeKind::MulAdd(targets) => {
+                let v = format!("v{}", self.uniq);
+                self.uniq += 1;
+                self.line(&format!("cell_t {v} = tape[p];"));
+                // Skipped on a zero cell, see `NodeKind::MulAdd`
+                self.line(&format!("if ({v}) {{"));
+                self.indent += 1;
+                for (offset, factor) in targets {
+                    let factor = wrap_to_cell(*factor, self.cell_bits);
+                    let op = if factor < 0 { '-' } else { '+' };
+                    self.line(&format!(
+                        "{} {op}= {v} * {};",
+                        self.cell(*offset, n.span),
+                        magnitude(factor)
+                    ));
+                }
+                self.line("tape[p] = 0;");
+                self.indent -= 1;
+                self.line("}");
+            }
+            NodeKind::Scan(step) => {
+                self.line("while (tape[p])");
+                self.indent += 1;
+                self.move_ptr(*step, n.span);
+                self.indent -= 1;
+            }
+            NodeKind::Output => self.line("bf_output(tape[p]);"),
+            NodeKind::Input => self.line("bf_input(&tape[p]);"),
+            NodeKind::LockAcquire => self.line("bf_lock_acquire(S, p);"),
+            NodeKind::LockRelease => self.line("bf_lock_release(S);"),
+            NodeKind::Wait => self.line("bf_wait(p);"),
+            NodeKind::Notify => self.line("bf_notify(p);"),
+            NodeKind::Sleep(t) => self.line(&format!("bf_sleep({t});")),
+            NodeKind::Loop(body) => {
+                self.line("while (tape[p]) {");
+                self.indent += 1;
+                self.emit_nodes(body);
+                self.indent -= 1;
+                self.line("}");
+            }
+            NodeKind::Parallel(branches) => self.emit_parallel(branches),
+        }
+    }
+
+    fn move_ptr(&mut self, delta: i64, span: Span) {
+        if self.bounds == TapeBounds::Unchecked {
+            self.line(&format!("p {};", compound(delta)));
+        } else {
+            let idx = self.index(delta, span);
+            self.line(&format!("p = {idx};"));
+        }
+    }
+
+    /// Tape index `offset` cells from the pointer, wrapped or checked per the bounds mode
+    fn index(&self, offset: i64, span: Span) -> String {
+        let offset = match self.bounds {
+            TapeBounds::Wrap => offset.rem_euclid(self.tape_len),
+            _ => offset,
+        };
+        let sum = match offset {
+            0 => return "p".to_string(),
+            o if o < 0 => format!("p - {}", o.unsigned_abs()),
+            o => format!("p + {o}"),
+        };
+        match self.bounds {
+            TapeBounds::Unchecked => sum,
+            TapeBounds::Checked => format!("bf_check({sum}, {}, {})", span.line, span.col),
+            TapeBounds::Wrap => format!("bf_wrap({sum})"),
+            TapeBounds::Grow => unreachable!("the C backend rejects --tape-grow"),
+        }
+    }
+
+    /// The cell `offset` away from the pointer
+    fn cell(&self, offset: i64, span: Span) -> String {
+        format!("tape[{}]", self.index(offset, span))
+    }
+
+    /// Start one thread per branch, each with its own State starting at `p`, then join them all
+    fn emit_parallel(&mut self, branches: &[Vec<Node>]) {
+        let pid = self.uniq;
+        self.uniq += 1;
+        let k = branches.len();
+        let threads = format!("threads{pid}");
+        let states = format!("states{pid}");
+        self.line(&format!("pthread_t {threads}[{k}];"));
+        self.line(&format!("State *{states}[{k}];"));
+        for (i, b) in branches.iter().enumerate() {
+            let name = format!("thread_p{pid}_{i}");
+            self.defer_thread(&name, b);
+            self.line(&format!("{states}[{i}] = bf_new_state(p);"));
+            self.line(&format!(
+                "pthread_create(&{threads}[{i}], NULL, {name}, {states}[{i}]);"
+            ));
+        }
+        self.line(&format!("for (int i = 0; i < {k}; i++) {{"));
+        self.line(&format!("    pthread_join({threads}[i], NULL);"));
+        self.line(&format!("    bf_free_state({states}[i]);"));
+        self.line("}");
+        // Flush at the join, see `OutputBuffering`
+        if self.output != OutputBuffering::None {
+            self.line("fflush(stdout);");
+        }
+    }
+}
+
+/// `+= n` or `-= n`, whichever keeps the operand positive
+fn compound(n: i64) -> String {
+    if n < 0 {
+        format!("-= {}", magnitude(n))
+    } else {
+        format!("+= {}", magnitude(n))
+    }
+}
+
+/// `|n|` as a C literal; magnitudes beyond `int` are unsigned so wide cells wrap instead of
+/// overflowing
+fn magnitude(n: i64) -> String {
+    let m = n.unsigned_abs();
+    if m > i32::MAX as u64 {
+        format!("{m}u")
+    } else {
+        m.to_string()
+    }
+}
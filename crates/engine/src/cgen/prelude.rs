@@ -0,0 +1,189 @@
+use super::CGen;
+use crate::codegen::{
+    Eof, GROW_CHUNK_BITS, LOCK_STACK_INIT, OUT_BUF_LEN, OutputBuffering, TapeBounds, bounds_message,
+};
+
+/// Headers, shared tape and sync objects, and the C counterparts of the IR runtime helpers.
+/// Helpers are `static inline`, so cc does not warn about the ones a program never calls.
+pub fn emit_prelude(g: &mut CGen) {
+    g.line("#define _POSIX_C_SOURCE 200809L /* nanosleep under -std=c11 */");
+    g.line("#include <pthread.h>");
+    if g.bounds == TapeBounds::Checked || g.sync {
+        g.line("#include <stdatomic.h>");
+    }
+    g.line("#include <stdint.h>");
+    g.line("#include <stdio.h>");
+    g.line("#include <stdlib.h>");
+    g.line("#include <time.h>");
+    g.line("");
+    g.line(&format!("#define TAPE_LEN {}", g.tape_len));
+    g.line(&format!("#define LOCK_STACK_INIT {LOCK_STACK_INIT}"));
+    g.line("");
+    g.line(&format!("typedef uint{}_t cell_t;", g.cell_bits));
+    g.line("");
+    g.line("static cell_t tape[TAPE_LEN];");
+    g.line("");
+    g.line("/* Per-thread state: start position and the stack of held locks */");
+    g.line("typedef struct {");
+    g.line("    int64_t ptr;");
+    g.line("    int64_t *stack;");
+    g.line("    int64_t sp, cap;");
+    g.line("} State;");
+    g.line("");
+    g.line("static inline State *bf_new_state(int64_t ptr) {");
+    g.line("    State *S = malloc(sizeof *S);");
+    g.line("    S->ptr = ptr;");
+    g.line("    S->stack = malloc(LOCK_STACK_INIT * sizeof *S->stack);");
+    g.line("    S->sp = 0;");
+    g.line("    S->cap = LOCK_STACK_INIT;");
+    g.line("    return S;");
+    g.line("}");
+    g.line("");
+    g.line("static inline void bf_free_state(State *S) {");
+    g.line("    free(S->stack);");
+    g.line("    free(S);");
+    g.line("}");
+    g.line("");
+    if g.sync {
+        emit_sync_helpers(g);
+    }
+    match g.bounds {
+        TapeBounds::Unchecked | TapeBounds::Grow => {}
+        TapeBounds::Checked => {
+            let msg = bounds_message("%ld", "%ld", g.bounds, g.tape_len, ("%ld", "%ld"));
+            g.line("static _Thread_local long bf_thread_id;");
+            g.line("");
+            g.line("static inline long bf_next_thread_id(void) {");
+            g.line("    static atomic_long count;");
+            g.line("    return atomic_fetch_add(&count, 1) + 1;");
+            g.line("}");
+            g.line("");
+            g.line(
+                "static inline _Noreturn void bf_bounds_fail(int64_t idx, long line, long col) {",
+            );
+            g.line("    fflush(stdout);");
+            g.line(&format!(
+                "    fprintf(stderr, \"{msg}\\n\", bf_thread_id, (long)idx, line, col);"
+            ));
+            g.line("    exit(1);");
+            g.line("}");
+            g.line("");
+            g.line("static inline int64_t bf_check(int64_t idx, long line, long col) {");
+            g.line("    if (idx < 0 || idx >= TAPE_LEN)");
+            g.line("        bf_bounds_fail(idx, line, col);");
+            g.line("    return idx;");
+            g.line("}");
+            g.line("");
+        }
+        TapeBounds::Wrap => {
+            g.line("/* Offsets are reduced modulo TAPE_LEN, so at most one lap is subtracted */");
+            g.line("static inline int64_t bf_wrap(int64_t idx) {");
+            g.line("    return idx >= TAPE_LEN ? idx - TAPE_LEN : idx;");
+            g.line("}");
+            g.line("");
+        }
+    }
+    g.line("/* Sleep for 0.1s per tick */");
+    g.line("static inline void bf_sleep(int ticks) {");
+    g.line("    int64_t ns = ticks * 100000000LL;");
+    g.line("    struct timespec ts = { ns / 1000000000LL, ns % 1000000000LL };");
+    g.line("    nanosleep(&ts, NULL);");
+    g.line("}");
+    g.line("");
+    g.line("/* Only the low byte of a wide cell is written */");
+    g.line("static inline void bf_output(cell_t v) {");
+    g.line("    putchar((unsigned char)v);");
+    if g.output == OutputBuffering::None {
+        g.line("    fflush(stdout);");
+    }
+    g.line("}");
+    g.line("");
+    g.line("static inline void bf_input(cell_t *p) {");
+    if g.output != OutputBuffering::None {
+        // Flush before input, see `OutputBuffering`
+        g.line("    fflush(stdout);");
+    }
+    g.line("    int c = getchar();");
+    g.line(match g.eof {
+        Eof::Unchanged => "    if (c >= 0) *p = (cell_t)c;",
+        Eof::Zero => "    *p = c < 0 ? 0 : (cell_t)c;",
+        Eof::MinusOne => "    *p = c < 0 ? (cell_t)-1 : (cell_t)c;",
+    });
+    g.line("}");
+    g.line("");
+    g.line("static inline void bf_init(void) {");
+    match g.output {
+        OutputBuffering::None => {}
+        OutputBuffering::Line => g.line(&format!(
+            "    setvbuf(stdout, NULL, _IOLBF, {OUT_BUF_LEN});"
+        )),
+        OutputBuffering::Full => g.line(&format!(
+            "    setvbuf(stdout, NULL, _IOFBF, {OUT_BUF_LEN});"
+        )),
+    }
+    g.line("}");
+    g.line("");
+}
+
+/// Per-cell sync objects, created a chunk at a time on first use, and the lock, wait and
+/// notify helpers built on them
+fn emit_sync_helpers(g: &mut CGen) {
+    g.line(&format!("#define SYNC_CHUNK {}", 1 << GROW_CHUNK_BITS));
+    g.line("");
+    g.line("/* A cell's mutex, condvar and condvar mutex */");
+    g.line("typedef struct {");
+    g.line("    pthread_mutex_t lock;");
+    g.line("    pthread_cond_t cond;");
+    g.line("    pthread_mutex_t cond_mtx;");
+    g.line("} Sync;");
+    g.line("");
+    g.line("static _Atomic(Sync *) sync_dir[(TAPE_LEN + SYNC_CHUNK - 1) / SYNC_CHUNK];");
+    g.line("");
+    g.line("/* Chunks are published with a compare-and-swap and never move, so the loser frees its copy */");
+    g.line("static inline Sync *bf_sync(int64_t idx) {");
+    g.line("    _Atomic(Sync *) *entry = &sync_dir[idx / SYNC_CHUNK];");
+    g.line("    Sync *chunk = atomic_load_explicit(entry, memory_order_acquire);");
+    g.line("    if (!chunk) {");
+    g.line("        Sync *fresh = malloc(SYNC_CHUNK * sizeof *fresh);");
+    g.line("        for (int64_t i = 0; i < SYNC_CHUNK; i++) {");
+    g.line("            pthread_mutex_init(&fresh[i].lock, NULL);");
+    g.line("            pthread_cond_init(&fresh[i].cond, NULL);");
+    g.line("            pthread_mutex_init(&fresh[i].cond_mtx, NULL);");
+    g.line("        }");
+    g.line("        if (atomic_compare_exchange_strong(entry, &chunk, fresh))");
+    g.line("            chunk = fresh;");
+    g.line("        else");
+    g.line("            free(fresh);");
+    g.line("    }");
+    g.line("    return &chunk[idx % SYNC_CHUNK];");
+    g.line("}");
+    g.line("");
+    g.line("static inline void bf_lock_acquire(State *S, int64_t idx) {");
+    g.line("    pthread_mutex_lock(&bf_sync(idx)->lock);");
+    g.line("    if (S->sp == S->cap) {");
+    g.line("        S->cap *= 2;");
+    g.line("        S->stack = realloc(S->stack, S->cap * sizeof *S->stack);");
+    g.line("    }");
+    g.line("    S->stack[S->sp++] = idx;");
+    g.line("}");
+    g.line("");
+    g.line("/* Releases the most recently acquired lock, wherever the pointer is now */");
+    g.line("static inline void bf_lock_release(State *S) {");
+    g.line("    pthread_mutex_unlock(&bf_sync(S->stack[--S->sp])->lock);");
+    g.line("}");
+    g.line("");
+    g.line("static inline void bf_wait(int64_t idx) {");
+    g.line("    Sync *s = bf_sync(idx);");
+    g.line("    pthread_mutex_lock(&s->cond_mtx);");
+    g.line("    pthread_cond_wait(&s->cond, &s->cond_mtx);");
+    g.line("    pthread_mutex_unlock(&s->cond_mtx);");
+    g.line("}");
+    g.line("");
+    g.line("static inline void bf_notify(int64_t idx) {");
+    g.line("    Sync *s = bf_sync(idx);");
+    g.line("    pthread_mutex_lock(&s->cond_mtx);");
+    g.line("    pthread_cond_broadcast(&s->cond);");
+    g.line("    pthread_mutex_unlock(&s->cond_mtx);");
+    g.line("}");
+    g.line("");
+}
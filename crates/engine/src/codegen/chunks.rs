@@ -0,0 +1,176 @@
+use super::{Codegen, GROW_CHUNK_BITS, GROW_CHUNKS, MUTEX_STRIDE, TapeBounds};
+
+// Lazily allocated memory lives in directories of chunk pointers. A growable tape
+// (`--tape-grow`) keeps its cells in `@tape_dir`, and every program that synchronizes keeps its
+// locks, condvars and condvar mutexes in `@sync_dir`, so a program only pays for the pthread
+// objects of the cells it actually locks or signals. Chunks are allocated on first touch and
+// published with a compare-and-swap, and never move afterwards, so a thread creating one cannot
+// invalidate the cell or mutex addresses another thread is using. A sync chunk holds the locks,
+// condvars and condvar mutexes of its cells, in that order.
+
+const CHUNK_LEN: i64 = 1 << GROW_CHUNK_BITS;
+
+/// Size of a directory, and the slot holding the chunk that starts at cell 0
+struct Dir {
+    len: i64,
+    origin: i64,
+}
+
+/// A growable tape reaches `GROW_LIMIT` cells either side of 0; a fixed one covers its length
+fn dir_for(g: &Codegen) -> Dir {
+    if g.bounds == TapeBounds::Grow {
+        Dir {
+            len: GROW_CHUNKS,
+            origin: GROW_CHUNKS / 2,
+        }
+    } else {
+        Dir {
+            len: (g.tape_len + CHUNK_LEN - 1) / CHUNK_LEN,
+            origin: 0,
+        }
+    }
+}
+
+/// Chunk directories, all null until the program touches them
+pub fn declare_globals(g: &mut Codegen) {
+    let i8_p = g.ptr("i8");
+    let len = dir_for(g).len;
+    if g.bounds == TapeBounds::Grow {
+        g.line(&format!(
+            "@tape_dir = internal global [{len} x {i8_p}] zeroinitializer"
+        ));
+    }
+    if g.sync {
+        g.line(&format!(
+            "@sync_dir = internal global [{len} x {i8_p}] zeroinitializer"
+        ));
+    }
+}
+
+pub fn define_helpers(g: &mut Codegen) {
+    let i8_pp = g.ptr(&g.ptr("i8"));
+    let i8_p = g.ptr("i8");
+    let cell = g.cell();
+    let cell_p = g.ptr(&cell);
+    let sync_bytes = 3 * CHUNK_LEN * MUTEX_STRIDE;
+
+    // bf_new_chunk(entry, bytes, sync): allocate a zeroed chunk, initialize its pthread objects
+    // for a sync chunk, and install it in the directory unless another thread got there first
+    g.line(&format!(
+        "define internal {i8_p} @bf_new_chunk({i8_pp} %entry, i64 %bytes, i1 %sync) noinline nounwind cold {{"
+    ));
+    g.indent += 1;
+    g.line("start:");
+    g.line(&format!("%fresh = call {i8_p} @calloc(i64 1, i64 %bytes)"));
+    g.line("br i1 %sync, label %init, label %publish");
+    g.line("init:");
+    g.line("%i = phi i64 [ 0, %start ], [ %i1, %init ]");
+    for (part, init) in [
+        (0, "pthread_mutex_init"),
+        (1, "pthread_cond_init"),
+        (2, "pthread_mutex_init"),
+    ] {
+        g.line(&format!("%n{part} = add i64 %i, {}", part * CHUNK_LEN));
+        g.line(&format!("%off{part} = mul i64 %n{part}, {MUTEX_STRIDE}"));
+        g.line(&format!(
+            "%slot{part} = getelementptr i8, {i8_p} %fresh, i64 %off{part}"
+        ));
+        g.line(&format!(
+            "call i32 @{init}({i8_p} %slot{part}, {i8_p} null)"
+        ));
+    }
+    g.line("%i1 = add i64 %i, 1");
+    g.line(&format!("%more = icmp ult i64 %i1, {CHUNK_LEN}"));
+    g.line("br i1 %more, label %init, label %publish");
+    g.line("publish:");
+    g.line(&format!(
+        "%pair = cmpxchg {i8_pp} %entry, {i8_p} null, {i8_p} %fresh acq_rel acquire"
+    ));
+    g.line(&format!("%old = extractvalue {{ {i8_p}, i1 }} %pair, 0"));
+    g.line(&format!("%won = extractvalue {{ {i8_p}, i1 }} %pair, 1"));
+    g.line("br i1 %won, label %mine, label %theirs");
+    g.line("mine:");
+    g.line(&format!("ret {i8_p} %fresh"));
+    g.line("theirs:");
+    g.line(&format!("call void @free({i8_p} %fresh)"));
+    g.line(&format!("ret {i8_p} %old"));
+    g.indent -= 1;
+    g.line("}");
+
+    if g.bounds == TapeBounds::Grow {
+        // bf_cell(idx): address of a cell, creating its chunk on first touch. `idx` is within
+        // GROW_LIMIT of cell 0, which `index_add` has already checked.
+        g.line(&format!(
+            "define internal {cell_p} @bf_cell(i64 %idx) alwaysinline nounwind {{"
+        ));
+        g.indent += 1;
+        g.line("start:");
+        let chunk = chunk_of(
+            g,
+            "tape_dir",
+            "%idx",
+            CHUNK_LEN * i64::from(g.cell_bits / 8),
+            false,
+        );
+        g.line(&format!("%cells = bitcast {i8_p} {chunk} to {cell_p}"));
+        g.line(&format!("%pos = and i64 %idx, {}", CHUNK_LEN - 1));
+        g.line(&format!(
+            "%p = getelementptr {cell}, {cell_p} %cells, i64 %pos"
+        ));
+        g.line(&format!("ret {cell_p} %p"));
+        g.indent -= 1;
+        g.line("}");
+    }
+
+    if g.sync {
+        // bf_sync_slot(idx, part): address of a cell's lock (part 0), condvar (1) or condvar
+        // mutex (2), creating the pthread objects of its chunk on first use
+        g.line(&format!(
+            "define internal {i8_p} @bf_sync_slot(i64 %idx, i64 %part) alwaysinline nounwind {{"
+        ));
+        g.indent += 1;
+        g.line("start:");
+        let chunk = chunk_of(g, "sync_dir", "%idx", sync_bytes, true);
+        g.line(&format!("%pos = and i64 %idx, {}", CHUNK_LEN - 1));
+        g.line(&format!("%first = mul i64 %part, {CHUNK_LEN}"));
+        g.line("%n = add i64 %first, %pos");
+        g.line(&format!("%off = mul i64 %n, {MUTEX_STRIDE}"));
+        g.line(&format!(
+            "%slot = getelementptr i8, {i8_p} {chunk}, i64 %off"
+        ));
+        g.line(&format!("ret {i8_p} %slot"));
+        g.indent -= 1;
+        g.line("}");
+    }
+}
+
+/// Load the chunk of `dir` holding cell `idx`, creating it when missing, from a function whose
+/// entry block is `start`. Returns the chunk pointer.
+fn chunk_of(g: &mut Codegen, dir: &str, idx: &str, bytes: i64, sync: bool) -> String {
+    let i8_pp = g.ptr(&g.ptr("i8"));
+    let i8_p = g.ptr("i8");
+    let Dir { len, origin } = dir_for(g);
+    let dir_ty = format!("[{len} x {i8_p}]");
+    let dir_p = g.ptr(&dir_ty);
+    // Arithmetic shift, so cells left of 0 land below the origin
+    g.line(&format!("%chunk = ashr i64 {idx}, {GROW_CHUNK_BITS}"));
+    g.line(&format!("%slot_i = add i64 %chunk, {origin}"));
+    g.line(&format!(
+        "%entry = getelementptr {dir_ty}, {dir_p} @{dir}, i64 0, i64 %slot_i"
+    ));
+    g.line(&format!(
+        "%have = load atomic {i8_p}, {i8_pp} %entry acquire, align 8"
+    ));
+    g.line(&format!("%missing = icmp eq {i8_p} %have, null"));
+    g.line("br i1 %missing, label %create, label %ready");
+    g.line("create:");
+    g.line(&format!(
+        "%made = call {i8_p} @bf_new_chunk({i8_pp} %entry, i64 {bytes}, i1 {sync})"
+    ));
+    g.line("br label %ready");
+    g.line("ready:");
+    g.line(&format!(
+        "%base = phi {i8_p} [ %have, %start ], [ %made, %create ]"
+    ));
+    "%base".to_string()
+}
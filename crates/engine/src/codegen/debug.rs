@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use super::Codegen;
+use crate::lexer::Span;
+
+/// Source file the generated debug info points at
+#[derive(Debug, Clone)]
+pub struct DebugSource {
+    pub filename: String,
+    pub directory: String,
+}
+
+// Fixed metadata nodes emitted before any subprogram or location
+const CU: usize = 0;
+const FILE: usize = 1;
+const SUBROUTINE_TYPE: usize = 2;
+const FIXED_NODES: usize = 6;
+
+/// DWARF metadata collected while emitting IR
+pub struct DebugInfo {
+    nodes: Vec<String>,   // Body of metadata node `!i` (without the `!i = ` prefix)
+    scope: Option<usize>, // DISubprogram of the thunk being emitted
+    loc: Option<usize>,   // DILocation attached to emitted calls
+    locations: HashMap<(usize, usize, usize), usize>, // DILocation by (line, column, scope)
+}
+
+/// `s` as an LLVM metadata string: printable ASCII other than `"` and `\` as is, every other
+/// byte as `\XX`
+fn quoted(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        if (b' '..=b'~').contains(&b) && b != b'"' && b != b'\\' {
+            out.push(b as char);
+        } else {
+            write!(out, "\\{b:02X}").unwrap();
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl DebugInfo {
+    pub fn new(src: &DebugSource) -> Self {
+        let mut nodes = vec![String::new(); FIXED_NODES];
+        nodes[CU] = format!(
+            "distinct !DICompileUnit(language: DW_LANG_C99, file: !{FILE}, producer: \"brainfork\", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug)"
+        );
+        nodes[FILE] = format!(
+            "!DIFile(filename: {}, directory: {})",
+            quoted(&src.filename),
+            quoted(&src.directory)
+        );
+        nodes[SUBROUTINE_TYPE] = "!DISubroutineType(types: !3)".to_string();
+        nodes[3] = "!{null}".to_string();
+        nodes[4] = "!{i32 2, !\"Debug Info Version\", i32 3}".to_string();
+        nodes[5] = "!{i32 7, !\"Dwarf Version\", i32 4}".to_string();
+        Self {
+            nodes,
+            scope: None,
+            loc: None,
+            locations: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, node: String) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+}
+
+/// Saved debug scope of an enclosing thunk, restored by [`Codegen::leave_subprogram`]
+pub struct SavedScope(Option<(Option<usize>, Option<usize>)>);
+
+impl Codegen {
+    /// Start a DISubprogram for `func`; returns the ` !dbg !N` suffix for its `define` line
+    pub fn enter_subprogram(&mut self, func: &str, line: usize) -> (SavedScope, String) {
+        let Some(di) = self.debug.as_mut() else {
+            return (SavedScope(None), String::new());
+        };
+        let saved = SavedScope(Some((di.scope, di.loc)));
+        let sp = di.push(format!(
+            "distinct !DISubprogram(name: {}, scope: !{FILE}, file: !{FILE}, line: {line}, type: !{SUBROUTINE_TYPE}, scopeLine: {line}, spFlags: DISPFlagDefinition, unit: !{CU})",
+            quoted(func)
+        ));
+        di.scope = Some(sp);
+        di.loc = None;
+        (saved, format!(" !dbg !{sp}"))
+    }
+
+    pub fn leave_subprogram(&mut self, saved: SavedScope) {
+        if let (Some(di), SavedScope(Some((scope, loc)))) = (self.debug.as_mut(), saved) {
+            di.scope = scope;
+            di.loc = loc;
+        }
+    }
+
+    /// Attribute the following instructions to `span`, reusing the DILocation of an earlier
+    /// instruction at the same place
+    pub fn set_location(&mut self, span: Span) {
+        if let Some(di) = self.debug.as_mut()
+            && let Some(scope) = di.scope
+        {
+            let key = (span.line, span.col, scope);
+            let loc = match di.locations.get(&key) {
+                Some(&loc) => loc,
+                None => {
+                    let loc = di.push(format!(
+                        "!DILocation(line: {}, column: {}, scope: !{scope})",
+                        span.line, span.col
+                    ));
+                    di.locations.insert(key, loc);
+                    loc
+                }
+            };
+            di.loc = Some(loc);
+        }
+    }
+
+    /// `, !dbg !N` suffix for an instruction at the current location, or empty
+    pub fn dbg(&self) -> String {
+        match self.debug.as_ref().and_then(|di| di.loc) {
+            Some(loc) => format!(", !dbg !{loc}"),
+            None => String::new(),
+        }
+    }
+
+    /// Emit named and numbered metadata at the end of the module
+    pub(super) fn debug_trailer(&mut self) {
+        let Some(di) = self.debug.take() else {
+            return;
+        };
+        self.line("");
+        self.line(&format!("!llvm.dbg.cu = !{{!{CU}}}"));
+        self.line("!llvm.module.flags = !{!4, !5}");
+        for (i, node) in di.nodes.iter().enumerate() {
+            self.line(&format!("!{i} = {node}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::quoted;
+
+    #[test]
+    fn quotes_metadata_strings_with_hex_escapes() {
+        assert_eq!(quoted("prog.bf"), r#""prog.bf""#);
+        assert_eq!(quoted(r#"a"b\c"#), r#""a\22b\5Cc""#);
+        assert_eq!(quoted("tab\tsé"), r#""tab\09s\C3\A9""#);
+    }
+}
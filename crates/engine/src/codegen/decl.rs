@@ -1,276 +1,501 @@
-use super::{Codegen, MUTEX_STRIDE};
+use super::{
+    Codegen, Eof, MUTEX_STRIDE, OUT_BUF_LEN, OutputBuffering, TapeBounds, bounds_message, chunks,
+};
 
 pub fn decl_externals(g: &mut Codegen) {
+    let fn_p = g.ptr("i8* (i8*)");
+    let st_p = g.ptr("%State");
+    let ts_p = g.ptr("%timespec");
+    let i8_pp = g.ptr(&g.ptr("i8"));
+    let i8_p = g.ptr("i8");
+    let i64_p = g.ptr("i64");
     g.line("declare i32 @putchar(i32)");
     g.line("declare i32 @getchar()");
-    g.line("declare i32 @fflush(i8*)");
-    g.line("declare i32 @nanosleep(%timespec*, %timespec*)");
-    g.line("declare i8* @malloc(i64)");
-    g.line("declare void @free(i8*)");
-    g.line("declare i32 @pthread_create(i64*, i8*, i8* (i8*)*, i8*)");
-    g.line("declare i32 @pthread_join(i64, i8**)");
-    g.line("declare i32 @pthread_mutex_init(i8*, i8*)");
-    g.line("declare i32 @pthread_mutex_lock(i8*)");
-    g.line("declare i32 @pthread_mutex_unlock(i8*)");
-    g.line("declare i32 @pthread_cond_init(i8*, i8*)");
-    g.line("declare i32 @pthread_cond_wait(i8*, i8*)");
-    g.line("declare i32 @pthread_cond_broadcast(i8*)");
+    g.line(&format!("declare i32 @fflush({i8_p})"));
+    g.line(&format!("declare i64 @write(i32, {i8_p}, i64)"));
+    g.line(&format!("declare i32 @nanosleep({ts_p}, {ts_p})"));
+    g.line(&format!("declare {i8_p} @malloc(i64)"));
+    g.line(&format!("declare void @free({i8_p})"));
+    g.line(&format!("declare {i8_p} @memchr({i8_p}, i32, i64)"));
+    g.line(&format!(
+        "declare i32 @pthread_create({i64_p}, {i8_p}, {fn_p}, {i8_p})"
+    ));
+    g.line(&format!("declare i32 @pthread_join(i64, {i8_pp})"));
+    g.line(&format!("declare i32 @pthread_mutex_init({i8_p}, {i8_p})"));
+    g.line(&format!("declare i32 @pthread_mutex_lock({i8_p})"));
+    g.line(&format!("declare i32 @pthread_mutex_unlock({i8_p})"));
+    g.line(&format!("declare i32 @pthread_cond_init({i8_p}, {i8_p})"));
+    g.line(&format!("declare i32 @pthread_cond_wait({i8_p}, {i8_p})"));
+    g.line(&format!("declare i32 @pthread_cond_broadcast({i8_p})"));
+    if g.bounds == TapeBounds::Grow || g.sync {
+        g.line(&format!("declare {i8_p} @calloc(i64, i64)"));
+    }
+    if g.bounds.reports() {
+        g.line(&format!("declare i32 @dprintf(i32, {i8_p}, ...)"));
+        g.line("declare void @exit(i32) noreturn");
+    }
 
     if g.sanitize {
         g.line("declare i64 @pthread_self()");
 
         // Thread sanitizer functions
-        g.line("declare void @tsan_read(%State*)");
-        g.line("declare void @tsan_write(%State*)");
-        g.line("declare void @tsan_acquire(%State*, i64)");
-        g.line("declare void @tsan_release(%State*, i64)");
+        g.line("declare void @tsan_init(i64)");
+        g.line(&format!("declare void @tsan_read({st_p})"));
+        g.line(&format!("declare void @tsan_write({st_p})"));
+        g.line(&format!("declare void @tsan_acquire({st_p}, i64)"));
+        g.line(&format!("declare void @tsan_release({st_p}, i64)"));
         g.line("declare void @tsan_fork(i64)");
         g.line("declare void @tsan_join(i64)");
-        g.line("declare void @tsan_pre_wait(%State*)");
-        g.line("declare void @tsan_post_wait(%State*)");
-        g.line("declare void @tsan_notify(%State*)");
+        g.line(&format!("declare void @tsan_pre_wait({st_p})"));
+        g.line(&format!("declare void @tsan_post_wait({st_p})"));
+        g.line(&format!("declare void @tsan_notify({st_p})"));
     }
 
     // memcpy intrinsic (used for expanding the lock stack)
-    g.line("declare void @llvm.memcpy.p0i8.p0i8.i64(i8* nocapture writeonly, i8* nocapture readonly, i64, i1 immarg)");
+    let memcpy = memcpy_intrinsic(g);
+    g.line(&format!("declare void @{memcpy}({i8_p} nocapture writeonly, {i8_p} nocapture readonly, i64, i1 immarg)"));
+}
+
+/// The memcpy intrinsic is overloaded on its pointer types, which are spelled into its name
+fn memcpy_intrinsic(g: &Codegen) -> &'static str {
+    if g.typed_pointers {
+        "llvm.memcpy.p0i8.p0i8.i64"
+    } else {
+        "llvm.memcpy.p0.p0.i64"
+    }
 }
 
 pub fn define_runtime_helpers(g: &mut Codegen) {
-    // GEP: pointer to current cell
-    g.line("define internal i8* @bf_gep_cell_ptr(%State* nocapture nonnull %S) alwaysinline nounwind {");
+    let st_p = g.ptr("%State");
+    let ts_p = g.ptr("%timespec");
+    let i64_pp = g.ptr(&g.ptr("i64"));
+    let i8_p = g.ptr("i8");
+    let i64_p = g.ptr("i64");
+    if g.bounds == TapeBounds::Grow || g.sync {
+        chunks::define_helpers(g);
+    }
+    if g.sync {
+        define_sync_helpers(g);
+    }
+
+    // Free a State and its lock stack, once the thread that used it has been joined
+    g.line(&format!(
+        "define internal void @bf_free_state({st_p} nonnull %S) nounwind {{"
+    ));
     g.indent += 1;
-    g.line("%fld_base = getelementptr %State, %State* %S, i32 0, i32 0");
-    g.line("%base = load i8*, i8** %fld_base");
-    g.line("%fld_idx = getelementptr %State, %State* %S, i32 0, i32 1");
-    g.line("%idx  = load i64,  i64*  %fld_idx");
-    g.line("%p    = getelementptr i8, i8* %base, i64 %idx");
-    g.line("ret i8* %p");
+    g.line(&format!(
+        "%fld_stk = getelementptr %State, {st_p} %S, i32 0, i32 2"
+    ));
+    g.line(&format!("%stk = load {i64_p}, {i64_pp} %fld_stk"));
+    g.line(&format!("%stk.raw = bitcast {i64_p} %stk to {i8_p}"));
+    g.line(&format!("call void @free({i8_p} %stk.raw)"));
+    g.line(&format!("%raw = bitcast {st_p} %S to {i8_p}"));
+    g.line(&format!("call void @free({i8_p} %raw)"));
+    g.line("ret void");
     g.indent -= 1;
     g.line("}");
 
-    // Address of lock slot (mutex_slab + idx * stride)
-    g.line("define internal i8* @bf_lock_slot_addr(%State* nocapture nonnull %S, i64 %idx) alwaysinline nounwind {");
+    // Forward unit-step scan: index of the first zero cell at or after idx
+    g.line(&format!(
+        "define internal i64 @bf_scan_fwd({i8_p} nonnull %base, i64 %idx) nounwind {{"
+    ));
     g.indent += 1;
-    g.line("%fld_slab = getelementptr %State, %State* %S, i32 0, i32 2");
-    g.line("%slab = load i8*, i8** %fld_slab");
-    g.line(&format!("%off  = mul i64 %idx, {MUTEX_STRIDE}"));
-    g.line("%slot = getelementptr i8, i8* %slab, i64 %off");
-    g.line("ret i8* %slot");
+    g.line(&format!(
+        "%start = getelementptr i8, {i8_p} %base, i64 %idx"
+    ));
+    g.line(&format!("%rest = sub i64 {}, %idx", g.tape_len));
+    g.line(&format!(
+        "%hit = call {i8_p} @memchr({i8_p} %start, i32 0, i64 %rest)"
+    ));
+    // No zero cell before the end of the tape: stop just past it, as the loop would run off
+    g.line(&format!("%found = icmp ne {i8_p} %hit, null"));
+    g.line(&format!("%hit_i = ptrtoint {i8_p} %hit to i64"));
+    g.line(&format!("%base_i = ptrtoint {i8_p} %base to i64"));
+    g.line("%hit_idx = sub i64 %hit_i, %base_i");
+    g.line(&format!(
+        "%new_idx = select i1 %found, i64 %hit_idx, i64 {}",
+        g.tape_len
+    ));
+    g.line("ret i64 %new_idx");
+    g.indent -= 1;
+    g.line("}");
+
+    // Output a cell value
+    if g.output == OutputBuffering::None {
+        g.line("define internal void @bf_output(i8 %v) nounwind {");
+        g.indent += 1;
+        g.line("%w = zext i8 %v to i32");
+        g.line("call i32 @putchar(i32 %w)");
+        g.line(&format!("call i32 @fflush({i8_p} null)"));
+        g.line("ret void");
+        g.indent -= 1;
+        g.line("}");
+    } else {
+        define_buffered_output(g);
+    }
+
+    // Input into a cell
+    let cell = g.cell();
+    let cell_p = g.ptr(&cell);
+    g.line(&format!(
+        "define internal void @bf_input({cell_p} nocapture nonnull %p) nounwind {{"
+    ));
+    g.indent += 1;
+    if g.output != OutputBuffering::None {
+        // Flush before input, see `OutputBuffering`
+        g.line("call void @bf_flush_out()");
+    }
+    g.line("%c = call i32 @getchar()");
+    g.line("%eof = icmp slt i32 %c, 0");
+    let value = match g.eof {
+        Eof::Unchanged => {
+            g.line("br i1 %eof, label %done, label %store");
+            g.line("store:");
+            "%c"
+        }
+        Eof::Zero => {
+            g.line("%cz = select i1 %eof, i32 0, i32 %c");
+            "%cz"
+        }
+        Eof::MinusOne => {
+            g.line("%cz = select i1 %eof, i32 -1, i32 %c");
+            "%cz"
+        }
+    };
+    match g.cell_bits {
+        32 => g.line(&format!("store i32 {value}, {cell_p} %p")),
+        bits => {
+            // A byte read is non-negative, so sign extension widens both it and -1 correctly
+            let conv = if bits < 32 { "trunc" } else { "sext" };
+            g.line(&format!("%b = {conv} i32 {value} to {cell}"));
+            g.line(&format!("store {cell} %b, {cell_p} %p"));
+        }
+    }
+    if g.eof == Eof::Unchanged {
+        g.line("br label %done");
+        g.line("done:");
+    }
+    g.line("ret void");
     g.indent -= 1;
     g.line("}");
 
+    if g.bounds.reports() {
+        define_bounds_fail(g);
+    }
+
+    // Sleep (0.1s * ticks)
+    g.line("define internal void @bf_sleep(i32 %ticks) nounwind {");
+    g.indent += 1;
+    g.line("%t64 = zext i32 %ticks to i64");
+    g.line("%ns_total = mul i64 %t64, 100000000");
+    g.line("%sec  = udiv i64 %ns_total, 1000000000");
+    g.line("%nsec = urem i64 %ns_total, 1000000000");
+    g.line("%ts = alloca %timespec");
+    g.line(&format!(
+        "%ts_sec  = getelementptr %timespec, {ts_p} %ts, i32 0, i32 0"
+    ));
+    g.line(&format!(
+        "%ts_nsec = getelementptr %timespec, {ts_p} %ts, i32 0, i32 1"
+    ));
+    g.line(&format!("store i64 %sec,  {i64_p} %ts_sec"));
+    g.line(&format!("store i64 %nsec, {i64_p} %ts_nsec"));
+    g.line(&format!("call i32 @nanosleep({ts_p} %ts, {ts_p} null)"));
+    g.line("ret void");
+    g.indent -= 1;
+    g.line("}");
+}
+
+/// Lock stack, lock, wait and notify helpers, only emitted for programs that synchronize
+fn define_sync_helpers(g: &mut Codegen) {
+    let st_p = g.ptr("%State");
+    let i64_pp = g.ptr(&g.ptr("i64"));
+    let i8_p = g.ptr("i8");
+    let i64_p = g.ptr("i64");
+
     // push_lock(%S, idx) with dynamic growth
-    g.line("define internal void @push_lock(%State* nocapture nonnull %S, i64 %idx) nounwind {");
+    g.line(&format!(
+        "define internal void @push_lock({st_p} nocapture nonnull %S, i64 %idx) nounwind {{"
+    ));
     g.indent += 1;
-    g.line("%fld_sp = getelementptr %State, %State* %S, i32 0, i32 4");
-    g.line("%sp  = load i64,  i64*  %fld_sp");
-    g.line("%fld_cap = getelementptr %State, %State* %S, i32 0, i32 5");
-    g.line("%cap = load i64,  i64*  %fld_cap");
+    g.line(&format!(
+        "%fld_sp = getelementptr %State, {st_p} %S, i32 0, i32 3"
+    ));
+    g.line(&format!("%sp  = load i64,  {i64_p}  %fld_sp"));
+    g.line(&format!(
+        "%fld_cap = getelementptr %State, {st_p} %S, i32 0, i32 4"
+    ));
+    g.line(&format!("%cap = load i64,  {i64_p}  %fld_cap"));
     // Precompute fld_buf before branch for dominance
-    g.line("%fld_buf = getelementptr %State, %State* %S, i32 0, i32 3");
+    g.line(&format!(
+        "%fld_buf = getelementptr %State, {st_p} %S, i32 0, i32 2"
+    ));
     g.line("%need_grow = icmp eq i64 %sp, %cap");
     g.line("br i1 %need_grow, label %grow, label %push");
 
     g.line("grow:");
-    g.line("%oldbuf = load i64*, i64** %fld_buf");
-    g.line("%oldcap = load i64, i64* %fld_cap");
+    g.line(&format!("%oldbuf = load {i64_p}, {i64_pp} %fld_buf"));
+    g.line(&format!("%oldcap = load i64, {i64_p} %fld_cap"));
     g.line("%newcap = shl i64 %oldcap, 1");
     g.line("%oldbytes = mul i64 %oldcap, 8");
     g.line("%newbytes = mul i64 %newcap, 8");
-    g.line("%newraw = call i8* @malloc(i64 %newbytes)");
-    g.line("%newbuf = bitcast i8* %newraw to i64*");
-    g.line("%dst = bitcast i64* %newbuf to i8*");
-    g.line("%src = bitcast i64* %oldbuf to i8*");
-    g.line("call void @llvm.memcpy.p0i8.p0i8.i64(i8* %dst, i8* %src, i64 %oldbytes, i1 false)");
-    g.line("call void @free(i8* %src)");
-    g.line("store i64* %newbuf, i64** %fld_buf");
-    g.line("store i64  %newcap, i64*  %fld_cap");
+    g.line(&format!("%newraw = call {i8_p} @malloc(i64 %newbytes)"));
+    g.line(&format!("%newbuf = bitcast {i8_p} %newraw to {i64_p}"));
+    g.line(&format!("%dst = bitcast {i64_p} %newbuf to {i8_p}"));
+    g.line(&format!("%src = bitcast {i64_p} %oldbuf to {i8_p}"));
+    let memcpy = memcpy_intrinsic(g);
+    g.line(&format!(
+        "call void @{memcpy}({i8_p} %dst, {i8_p} %src, i64 %oldbytes, i1 false)"
+    ));
+    g.line(&format!("call void @free({i8_p} %src)"));
+    g.line(&format!("store {i64_p} %newbuf, {i64_pp} %fld_buf"));
+    g.line(&format!("store i64  %newcap, {i64_p}  %fld_cap"));
     g.line("br label %push");
 
     g.line("push:");
-    g.line("%buf = load i64*, i64** %fld_buf");
-    g.line("%slotp = getelementptr i64, i64* %buf, i64 %sp");
-    g.line("store i64 %idx, i64* %slotp");
+    g.line(&format!("%buf = load {i64_p}, {i64_pp} %fld_buf"));
+    g.line(&format!(
+        "%slotp = getelementptr i64, {i64_p} %buf, i64 %sp"
+    ));
+    g.line(&format!("store i64 %idx, {i64_p} %slotp"));
     g.line("%sp1 = add i64 %sp, 1");
-    g.line("store i64 %sp1, i64* %fld_sp");
+    g.line(&format!("store i64 %sp1, {i64_p} %fld_sp"));
     g.line("ret void");
     g.indent -= 1;
     g.line("}");
 
     // pop_lock(%S) -> i64 (caller assumes non-empty stack)
-    g.line("define internal i64 @pop_lock(%State* nocapture nonnull %S) nounwind {");
+    g.line(&format!(
+        "define internal i64 @pop_lock({st_p} nocapture nonnull %S) nounwind {{"
+    ));
     g.indent += 1;
-    g.line("%fld_sp2 = getelementptr %State, %State* %S, i32 0, i32 4");
-    g.line("%sp  = load i64, i64* %fld_sp2");
+    g.line(&format!(
+        "%fld_sp2 = getelementptr %State, {st_p} %S, i32 0, i32 3"
+    ));
+    g.line(&format!("%sp  = load i64, {i64_p} %fld_sp2"));
     g.line("%sp1 = add i64 %sp, -1");
-    g.line("store i64 %sp1, i64* %fld_sp2");
-    g.line("%fld_buf2 = getelementptr %State, %State* %S, i32 0, i32 3");
-    g.line("%buf = load i64*, i64** %fld_buf2");
-    g.line("%slotp = getelementptr i64, i64* %buf, i64 %sp1");
-    g.line("%idx = load i64, i64* %slotp");
+    g.line(&format!("store i64 %sp1, {i64_p} %fld_sp2"));
+    g.line(&format!(
+        "%fld_buf2 = getelementptr %State, {st_p} %S, i32 0, i32 2"
+    ));
+    g.line(&format!("%buf = load {i64_p}, {i64_pp} %fld_buf2"));
+    g.line(&format!(
+        "%slotp = getelementptr i64, {i64_p} %buf, i64 %sp1"
+    ));
+    g.line(&format!("%idx = load i64, {i64_p} %slotp"));
     g.line("ret i64 %idx");
     g.indent -= 1;
     g.line("}");
 
-    // Primitive: pointer movement
-    g.line("define internal void @bf_inc_ptr(%State* nocapture nonnull %S, i64 %delta) alwaysinline nounwind {");
-    g.indent += 1;
-    g.line("%fld_ptr = getelementptr %State,%State* %S, i32 0, i32 1");
-    g.line("%v = load i64, i64* %fld_ptr");
-    g.line("%u = add i64 %v, %delta");
-    g.line("store i64 %u, i64* %fld_ptr");
-    g.line("ret void");
-    g.indent -= 1;
-    g.line("}");
-
-    // Primitive: add to cell (non-atomic)
-    g.line("define internal void @bf_add_cell(%State* nocapture nonnull %S, i32 %delta) alwaysinline nounwind {");
+    // Acquire lock (then push)
+    g.line(&format!(
+        "define internal void @bf_lock_acquire({st_p} nocapture nonnull %S) nounwind {{"
+    ));
     g.indent += 1;
-    g.line("%p = call i8* @bf_gep_cell_ptr(%State* %S)");
-    if g.sanitize {
-        g.line("call void @tsan_read(%State* %S)");
-    }
-    g.line("%v0 = load i8, i8* %p");
-    g.line("%d8 = trunc i32 %delta to i8");
-    g.line("%v1 = add i8 %v0, %d8");
+    g.line(&format!(
+        "%fld_ptr2 = getelementptr %State,{st_p} %S, i32 0, i32 1"
+    ));
+    g.line(&format!("%idx = load i64, {i64_p} %fld_ptr2"));
+    g.line(&format!(
+        "%slot = call {i8_p} @bf_sync_slot(i64 %idx, i64 0)"
+    ));
+    g.line(&format!("call i32 @pthread_mutex_lock({i8_p} %slot)"));
+    g.line(&format!("call void @push_lock({st_p} %S, i64 %idx)"));
     if g.sanitize {
-        g.line("call void @tsan_write(%State* %S)");
+        g.line(&format!("call void @tsan_acquire({st_p} %S, i64 %idx)"));
     }
-    g.line("store i8 %v1, i8* %p");
     g.line("ret void");
     g.indent -= 1;
     g.line("}");
 
-    // Output
-    g.line("define internal void @bf_output(%State* nocapture nonnull %S) nounwind {");
+    // Release lock (pop then unlock)
+    g.line(&format!(
+        "define internal void @bf_lock_release({st_p} nocapture nonnull %S) nounwind {{"
+    ));
     g.indent += 1;
-    g.line("%p = call i8* @bf_gep_cell_ptr(%State* %S)");
+    g.line(&format!("%idx = call i64 @pop_lock({st_p} %S)"));
+    g.line(&format!(
+        "%slot = call {i8_p} @bf_sync_slot(i64 %idx, i64 0)"
+    ));
+    g.line(&format!("call i32 @pthread_mutex_unlock({i8_p} %slot)"));
     if g.sanitize {
-        g.line("call void @tsan_read(%State* %S)");
+        g.line(&format!("call void @tsan_release({st_p} %S, i64 %idx)"));
     }
-    g.line("%v = load i8, i8* %p");
-    g.line("%w = zext i8 %v to i32");
-    g.line("call i32 @putchar(i32 %w)");
-    g.line("call i32 @fflush(i8* null)");
     g.line("ret void");
     g.indent -= 1;
     g.line("}");
 
-    // Input
-    g.line("define internal void @bf_input(%State* nocapture nonnull %S) nounwind {");
+    // Wait: lock cond-mutex -> cond_wait -> unlock
+    g.line(&format!(
+        "define internal void @bf_wait({st_p} nocapture nonnull %S) nounwind {{"
+    ));
     g.indent += 1;
-    g.line("%c = call i32 @getchar()");
-    g.line("%eof = icmp slt i32 %c, 0");
-    g.line("%cz = select i1 %eof, i32 0, i32 %c");
-    g.line("%b = trunc i32 %cz to i8");
-    g.line("%p = call i8* @bf_gep_cell_ptr(%State* %S)");
+    g.line(&format!(
+        "%fld_ptrW = getelementptr %State, {st_p} %S, i32 0, i32 1"
+    ));
+    g.line(&format!("%idxW = load i64, {i64_p} %fld_ptrW"));
+    g.line(&format!(
+        "%cmW = call {i8_p} @bf_sync_slot(i64 %idxW, i64 2)"
+    ));
+    g.line(&format!(
+        "%cvW = call {i8_p} @bf_sync_slot(i64 %idxW, i64 1)"
+    ));
+    g.line(&format!("call i32 @pthread_mutex_lock({i8_p} %cmW)"));
     if g.sanitize {
-        g.line("call void @tsan_write(%State* %S)");
+        g.line(&format!("call void @tsan_pre_wait({st_p} %S)"));
     }
-    g.line("store i8 %b, i8* %p");
-    g.line("ret void");
-    g.indent -= 1;
-    g.line("}");
-
-    // Sleep (0.1s * ticks)
-    g.line("define internal void @bf_sleep(i32 %ticks) nounwind {");
-    g.indent += 1;
-    g.line("%t64 = zext i32 %ticks to i64");
-    g.line("%ns_total = mul i64 %t64, 100000000");
-    g.line("%sec  = udiv i64 %ns_total, 1000000000");
-    g.line("%nsec = urem i64 %ns_total, 1000000000");
-    g.line("%ts = alloca %timespec");
-    g.line("%ts_sec  = getelementptr %timespec, %timespec* %ts, i32 0, i32 0");
-    g.line("%ts_nsec = getelementptr %timespec, %timespec* %ts, i32 0, i32 1");
-    g.line("store i64 %sec,  i64* %ts_sec");
-    g.line("store i64 %nsec, i64* %ts_nsec");
-    g.line("call i32 @nanosleep(%timespec* %ts, %timespec* null)");
-    g.line("ret void");
-    g.indent -= 1;
-    g.line("}");
-
-    // Acquire lock (then push)
-    g.line("define internal void @bf_lock_acquire(%State* nocapture nonnull %S) nounwind {");
-    g.indent += 1;
-    g.line("%fld_ptr2 = getelementptr %State,%State* %S, i32 0, i32 1");
-    g.line("%idx = load i64, i64* %fld_ptr2");
-    g.line("%slot = call i8* @bf_lock_slot_addr(%State* %S, i64 %idx)");
-    g.line("call i32 @pthread_mutex_lock(i8* %slot)");
-    g.line("call void @push_lock(%State* %S, i64 %idx)");
+    g.line(&format!(
+        "call i32 @pthread_cond_wait({i8_p} %cvW, {i8_p} %cmW)"
+    ));
     if g.sanitize {
-        g.line("call void @tsan_acquire(%State* %S, i64 %idx)");
+        g.line(&format!("call void @tsan_post_wait({st_p} %S)"));
     }
+    g.line(&format!("call i32 @pthread_mutex_unlock({i8_p} %cmW)"));
     g.line("ret void");
     g.indent -= 1;
     g.line("}");
 
-    // Release lock (pop then unlock)
-    g.line("define internal void @bf_lock_release(%State* nocapture nonnull %S) nounwind {");
+    // Notify: lock cond-mutex -> broadcast -> unlock
+    g.line(&format!(
+        "define internal void @bf_notify({st_p} nocapture nonnull %S) nounwind {{"
+    ));
     g.indent += 1;
-    g.line("%idx = call i64 @pop_lock(%State* %S)");
-    g.line("%slot = call i8* @bf_lock_slot_addr(%State* %S, i64 %idx)");
-    g.line("call i32 @pthread_mutex_unlock(i8* %slot)");
+    g.line(&format!(
+        "%fld_ptrN = getelementptr %State, {st_p} %S, i32 0, i32 1"
+    ));
+    g.line(&format!("%idxN = load i64, {i64_p} %fld_ptrN"));
+    g.line(&format!(
+        "%cmN = call {i8_p} @bf_sync_slot(i64 %idxN, i64 2)"
+    ));
+    g.line(&format!(
+        "%cvN = call {i8_p} @bf_sync_slot(i64 %idxN, i64 1)"
+    ));
+    g.line(&format!("call i32 @pthread_mutex_lock({i8_p} %cmN)"));
     if g.sanitize {
-        g.line("call void @tsan_release(%State* %S, i64 %idx)");
+        g.line(&format!("call void @tsan_notify({st_p} %S)"));
     }
+    g.line(&format!("call i32 @pthread_cond_broadcast({i8_p} %cvN)"));
+    g.line(&format!("call i32 @pthread_mutex_unlock({i8_p} %cmN)"));
     g.line("ret void");
     g.indent -= 1;
     g.line("}");
+}
 
-    // Address of condvar slot: cond_slab + idx * stride
-    g.line("define internal i8* @bf_cond_slot_addr(%State* nocapture nonnull %S, i64 %idx) alwaysinline nounwind {");
+/// Output through the process-wide buffer `@outbuf`, written to fd 1 by `bf_flush_out`
+fn define_buffered_output(g: &mut Codegen) {
+    let buf_ty = format!("[{OUT_BUF_LEN} x i8]");
+    let mtx_ty = format!("[{MUTEX_STRIDE} x i8]");
+    let buf_p = g.ptr(&buf_ty);
+    let mtx_p = g.ptr(&mtx_ty);
+    let i8_p = g.ptr("i8");
+    let i64_p = g.ptr("i64");
+
+    // Write out and empty the buffer (caller holds @outmtx)
+    g.line("define internal void @bf_flush_out_locked() nounwind {");
     g.indent += 1;
-    g.line("%csl = load i8*, i8** @cond_slab");
-    g.line(&format!("%coff = mul i64 %idx, {MUTEX_STRIDE}"));
-    g.line("%cslot = getelementptr i8, i8* %csl, i64 %coff");
-    g.line("ret i8* %cslot");
+    g.line("entry:");
+    g.line(&format!(
+        "%buf = getelementptr {buf_ty}, {buf_p} @outbuf, i64 0, i64 0"
+    ));
+    g.line(&format!("%len = load i64, {i64_p} @outlen"));
+    g.line("br label %loop");
+    g.line("loop:");
+    g.line("%done = phi i64 [ 0, %entry ], [ %done1, %wrote ]");
+    g.line("%more = icmp ult i64 %done, %len");
+    g.line("br i1 %more, label %write, label %exit");
+    g.line("write:");
+    g.line(&format!("%src = getelementptr i8, {i8_p} %buf, i64 %done"));
+    g.line("%rest = sub i64 %len, %done");
+    g.line(&format!(
+        "%n = call i64 @write(i32 1, {i8_p} %src, i64 %rest)"
+    ));
+    // Give up on the remaining bytes if stdout is gone
+    g.line("%ok = icmp sgt i64 %n, 0");
+    g.line("br i1 %ok, label %wrote, label %exit");
+    g.line("wrote:");
+    g.line("%done1 = add i64 %done, %n");
+    g.line("br label %loop");
+    g.line("exit:");
+    g.line(&format!("store i64 0, {i64_p} @outlen"));
+    g.line("ret void");
     g.indent -= 1;
     g.line("}");
 
-    // Address of cond-mutex slot: cond_mtx_slab + idx * stride
-    g.line("define internal i8* @bf_cmtx_slot_addr(%State* nocapture nonnull %S, i64 %idx) alwaysinline nounwind {");
+    g.line("define internal void @bf_flush_out() nounwind {");
     g.indent += 1;
-    g.line("%msl = load i8*, i8** @cond_mtx_slab");
-    g.line(&format!("%moff = mul i64 %idx, {MUTEX_STRIDE}"));
-    g.line("%mslot = getelementptr i8, i8* %msl, i64 %moff");
-    g.line("ret i8* %mslot");
+    g.line(&format!(
+        "%m = getelementptr {mtx_ty}, {mtx_p} @outmtx, i64 0, i64 0"
+    ));
+    g.line(&format!("call i32 @pthread_mutex_lock({i8_p} %m)"));
+    g.line("call void @bf_flush_out_locked()");
+    g.line(&format!("call i32 @pthread_mutex_unlock({i8_p} %m)"));
+    g.line("ret void");
     g.indent -= 1;
     g.line("}");
 
-    // Wait: lock cond-mutex -> cond_wait -> unlock
-    g.line("define internal void @bf_wait(%State* nocapture nonnull %S) nounwind {");
+    g.line("define internal void @bf_output(i8 %v) nounwind {");
     g.indent += 1;
-    g.line("%fld_ptrW = getelementptr %State, %State* %S, i32 0, i32 1");
-    g.line("%idxW = load i64, i64* %fld_ptrW");
-    g.line("%cmW = call i8* @bf_cmtx_slot_addr(%State* %S, i64 %idxW)");
-    g.line("%cvW = call i8* @bf_cond_slot_addr(%State* %S, i64 %idxW)");
-    g.line("call i32 @pthread_mutex_lock(i8* %cmW)");
-    if g.sanitize {
-        g.line("call void @tsan_pre_wait(%State* %S)");
-    }
-    g.line("call i32 @pthread_cond_wait(i8* %cvW, i8* %cmW)");
-    if g.sanitize {
-        g.line("call void @tsan_post_wait(%State* %S)");
-    }
-    g.line("call i32 @pthread_mutex_unlock(i8* %cmW)");
+    g.line("entry:");
+    g.line(&format!(
+        "%m = getelementptr {mtx_ty}, {mtx_p} @outmtx, i64 0, i64 0"
+    ));
+    g.line(&format!("call i32 @pthread_mutex_lock({i8_p} %m)"));
+    g.line(&format!("%len = load i64, {i64_p} @outlen"));
+    g.line(&format!(
+        "%slot = getelementptr {buf_ty}, {buf_p} @outbuf, i64 0, i64 %len"
+    ));
+    g.line(&format!("store i8 %v, {i8_p} %slot"));
+    g.line("%len1 = add i64 %len, 1");
+    g.line(&format!("store i64 %len1, {i64_p} @outlen"));
+    g.line(&format!("%full = icmp eq i64 %len1, {OUT_BUF_LEN}"));
+    let flush = if g.output == OutputBuffering::Line {
+        g.line("%nl = icmp eq i8 %v, 10");
+        g.line("%flush = or i1 %full, %nl");
+        "%flush"
+    } else {
+        "%full"
+    };
+    g.line(&format!("br i1 {flush}, label %do_flush, label %unlock"));
+    g.line("do_flush:");
+    g.line("call void @bf_flush_out_locked()");
+    g.line("br label %unlock");
+    g.line("unlock:");
+    g.line(&format!("call i32 @pthread_mutex_unlock({i8_p} %m)"));
     g.line("ret void");
     g.indent -= 1;
     g.line("}");
+}
 
-    // Notify: lock cond-mutex -> broadcast -> unlock
-    g.line("define internal void @bf_notify(%State* nocapture nonnull %S) nounwind {");
+/// Report a pointer outside the tape on stderr and exit. Output written so far is flushed
+/// first so the report follows it.
+fn define_bounds_fail(g: &mut Codegen) {
+    let i8_p = g.ptr("i8");
+    let i64_p = g.ptr("i64");
+    let msg = format!(
+        "{}\n",
+        bounds_message("%ld", "%ld", g.bounds, g.tape_len, ("%ld", "%ld"))
+    );
+    let msg_ty = format!("[{} x i8]", msg.len() + 1);
+    let msg_p = g.ptr(&msg_ty);
+    g.line(&format!(
+        "@bounds_msg = private unnamed_addr constant {msg_ty} c\"{}\\00\"",
+        msg.replace('\n', "\\0A")
+    ));
+    g.line(
+        "define internal void @bf_bounds_fail(i64 %idx, i64 %line, i64 %col) noreturn nounwind cold {",
+    );
     g.indent += 1;
-    g.line("%fld_ptrN = getelementptr %State, %State* %S, i32 0, i32 1");
-    g.line("%idxN = load i64, i64* %fld_ptrN");
-    g.line("%cmN = call i8* @bf_cmtx_slot_addr(%State* %S, i64 %idxN)");
-    g.line("%cvN = call i8* @bf_cond_slot_addr(%State* %S, i64 %idxN)");
-    g.line("call i32 @pthread_mutex_lock(i8* %cmN)");
-    if g.sanitize {
-        g.line("call void @tsan_notify(%State* %S)");
+    if g.output != OutputBuffering::None {
+        g.line("call void @bf_flush_out()");
     }
-    g.line("call i32 @pthread_cond_broadcast(i8* %cvN)");
-    g.line("call i32 @pthread_mutex_unlock(i8* %cmN)");
-    g.line("ret void");
+    g.line(&format!("%tid = load i64, {i64_p} @bf_thread_id"));
+    g.line(&format!(
+        "%fmt = getelementptr {msg_ty}, {msg_p} @bounds_msg, i64 0, i64 0"
+    ));
+    g.line(&format!(
+        "call i32 (i32, {i8_p}, ...) @dprintf(i32 2, {i8_p} %fmt, i64 %tid, i64 %idx, i64 %line, i64 %col)"
+    ));
+    g.line("call void @exit(i32 1)");
+    g.line("unreachable");
     g.indent -= 1;
     g.line("}");
 }
@@ -1,5 +1,30 @@
-use super::{Codegen, parallel};
-use crate::parser::Node;
+use super::{Codegen, GROW_LIMIT, TapeBounds, parallel, wrap_to_cell};
+use crate::lexer::Span;
+use crate::parser::{Node, NodeKind};
+
+// Inside a thunk the pointer index lives in the local `%ptr.slot` (promoted to an SSA register
+// by mem2reg) and the tape base in `%tape`. The State's pointer field `%S.ptr` is only written
+// right before calls that read it: sanitizer hooks, lock and condvar helpers and thread spawns.
+
+/// Load the tape base and pointer index into locals at the top of a thunk
+pub fn emit_prologue(g: &mut Codegen, s: &str) {
+    let st_p = g.ptr("%State");
+    let i8_pp = g.ptr(&g.ptr("i8"));
+    let i8_p = g.ptr("i8");
+    let cell_p = g.ptr(&g.cell());
+    let i64_p = g.ptr("i64");
+    g.line(&format!(
+        "%S.base = getelementptr %State, {st_p} {s}, i32 0, i32 0"
+    ));
+    g.line(&format!("%tape.raw = load {i8_p}, {i8_pp} %S.base"));
+    g.line(&format!("%tape = bitcast {i8_p} %tape.raw to {cell_p}"));
+    g.line(&format!(
+        "%S.ptr = getelementptr %State, {st_p} {s}, i32 0, i32 1"
+    ));
+    g.line(&format!("%ptr.init = load i64, {i64_p} %S.ptr"));
+    g.line("%ptr.slot = alloca i64");
+    g.line(&format!("store i64 %ptr.init, {i64_p} %ptr.slot"));
+}
 
 pub fn emit_nodes(g: &mut Codegen, s: &str, nodes: &[Node]) {
     for n in nodes {
@@ -8,43 +33,265 @@ pub fn emit_nodes(g: &mut Codegen, s: &str, nodes: &[Node]) {
 }
 
 fn emit_node(g: &mut Codegen, s: &str, n: &Node) {
-    match n {
-        Node::IncPtr => g.line(&format!("call void @bf_inc_ptr(%State* {s}, i64 1)")),
-        Node::DecPtr => g.line(&format!("call void @bf_inc_ptr(%State* {s}, i64 -1)")),
-        Node::IncCell => g.line(&format!("call void @bf_add_cell(%State* {s}, i32 1)")),
-        Node::DecCell => g.line(&format!("call void @bf_add_cell(%State* {s}, i32 -1)")),
-        Node::Output => g.line(&format!("call void @bf_output(%State* {s})")),
-        Node::Input => g.line(&format!("call void @bf_input(%State* {s})")),
-        Node::LockAcquire => g.line(&format!("call void @bf_lock_acquire(%State* {s})")),
-        Node::LockRelease => g.line(&format!("call void @bf_lock_release(%State* {s})")),
-        Node::Sleep(t) => g.line(&format!("call void @bf_sleep(i32 {t})")),
-        Node::Wait => g.line(&format!("call void @bf_wait(%State* {s})")),
-        Node::Notify => g.line(&format!("call void @bf_notify(%State* {s})")),
-        Node::Loop(body) => emit_loop(g, s, body),
-        Node::Parallel(bs) => parallel::emit_parallel(g, s, bs),
-    }
-}
-
-fn emit_loop(g: &mut Codegen, s: &str, body: &[Node]) {
+    let cell = g.cell();
+    let cell_p = g.ptr(&cell);
+    g.set_location(n.span);
+    match &n.kind {
+        NodeKind::IncPtr => move_ptr(g, 1, n.span),
+        NodeKind::DecPtr => move_ptr(g, -1, n.span),
+        NodeKind::Move(d) => move_ptr(g, *d, n.span),
+        NodeKind::IncCell => add_cell(g, s, 1, n.span),
+        NodeKind::DecCell => add_cell(g, s, -1, n.span),
+        NodeKind::Add(d) => add_cell(g, s, *d, n.span),
+        NodeKind::Clear => {
+            let (idx, p) = cell_at(g, 0, n.span);
+            hook(g, s, "write", &idx);
+            g.inst(&format!("store {cell} 0, {cell_p} {p}"));
+        }
+        NodeKind::MulAdd(targets) => emit_mul_add(g, s, targets, n.span),
+        NodeKind::Scan(step) => emit_scan(g, s, *step, n.span),
+        NodeKind::Output => {
+            let (idx, p) = cell_at(g, 0, n.span);
+            hook(g, s, "read", &idx);
+            let v = g.fresh("v");
+            g.inst(&format!("{v} = load {cell}, {cell_p} {p}"));
+            // Only the low byte of a wide cell is written
+            let byte = if g.cell_bits == 8 {
+                v
+            } else {
+                let b = g.fresh("b");
+                g.inst(&format!("{b} = trunc {cell} {v} to i8"));
+                b
+            };
+            g.inst(&format!("call void @bf_output(i8 {byte})"));
+        }
+        NodeKind::Input => {
+            let (idx, p) = cell_at(g, 0, n.span);
+            hook(g, s, "write", &idx);
+            g.inst(&format!("call void @bf_input({cell_p} {p})"));
+        }
+        NodeKind::LockAcquire => state_call(g, s, "bf_lock_acquire"),
+        NodeKind::LockRelease => state_call(g, s, "bf_lock_release"),
+        NodeKind::Wait => state_call(g, s, "bf_wait"),
+        NodeKind::Notify => state_call(g, s, "bf_notify"),
+        NodeKind::Sleep(t) => g.inst(&format!("call void @bf_sleep(i32 {t})")),
+        NodeKind::Loop(body) => emit_loop(g, s, n, body),
+        NodeKind::Parallel(bs) => {
+            spill(g);
+            parallel::emit_parallel(g, s, bs);
+        }
+    }
+}
+
+/// Current pointer index plus `offset`, and the address of that cell
+fn cell_at(g: &mut Codegen, offset: i64, span: Span) -> (String, String) {
+    let cell = g.cell();
+    let cell_p = g.ptr(&cell);
+    let i64_p = g.ptr("i64");
+    let cur = g.fresh("idx");
+    g.inst(&format!("{cur} = load i64, {i64_p} %ptr.slot"));
+    let idx = index_add(g, &cur, offset, span);
+    let p = g.fresh("cell");
+    if g.bounds == TapeBounds::Grow {
+        g.inst(&format!("{p} = call {cell_p} @bf_cell(i64 {idx})"));
+    } else {
+        g.inst(&format!(
+            "{p} = getelementptr {cell}, {cell_p} %tape, i64 {idx}"
+        ));
+    }
+    (idx, p)
+}
+
+/// Sanitizer hook for an access to the cell at `idx` (no-op without sanitization)
+fn hook(g: &mut Codegen, s: &str, kind: &str, idx: &str) {
+    let st_p = g.ptr("%State");
+    let i64_p = g.ptr("i64");
+    if g.sanitize {
+        g.inst(&format!("store i64 {idx}, {i64_p} %S.ptr"));
+        g.inst(&format!("call void @tsan_{kind}({st_p} {s})"));
+    }
+}
+
+/// Write the cached pointer index back to the State
+fn spill(g: &mut Codegen) {
+    let i64_p = g.ptr("i64");
+    let idx = g.fresh("idx");
+    g.inst(&format!("{idx} = load i64, {i64_p} %ptr.slot"));
+    g.inst(&format!("store i64 {idx}, {i64_p} %S.ptr"));
+}
+
+/// Call a runtime helper that reads the pointer from the State
+fn state_call(g: &mut Codegen, s: &str, helper: &str) {
+    let st_p = g.ptr("%State");
+    spill(g);
+    g.inst(&format!("call void @{helper}({st_p} {s})"));
+}
+
+fn move_ptr(g: &mut Codegen, delta: i64, span: Span) {
+    let i64_p = g.ptr("i64");
+    let cur = g.fresh("idx");
+    g.inst(&format!("{cur} = load i64, {i64_p} %ptr.slot"));
+    let next = index_add(g, &cur, delta, span);
+    g.inst(&format!("store i64 {next}, {i64_p} %ptr.slot"));
+}
+
+/// Tape index `cur + delta` under the bounds mode: wrapped around the tape, or checked and
+/// reported at `span` when it falls outside the tape (or a growable tape's limit)
+fn index_add(g: &mut Codegen, cur: &str, delta: i64, span: Span) -> String {
+    let len = g.tape_len;
+    // Without wrapping, a zero offset cannot leave the tape
+    let delta = match g.bounds {
+        TapeBounds::Wrap => delta.rem_euclid(len),
+        _ => delta,
+    };
+    if delta == 0 {
+        return cur.to_string();
+    }
+    let idx = g.fresh("idx");
+    match g.bounds {
+        TapeBounds::Unchecked => g.inst(&format!("{idx} = add i64 {cur}, {delta}")),
+        TapeBounds::Wrap => {
+            // `delta` is below the tape length, so at most one lap is subtracted
+            let sum = g.fresh("idx");
+            let over = g.fresh("over");
+            let back = g.fresh("idx");
+            g.inst(&format!("{sum} = add i64 {cur}, {delta}"));
+            g.inst(&format!("{over} = icmp uge i64 {sum}, {len}"));
+            g.inst(&format!("{back} = sub i64 {sum}, {len}"));
+            g.inst(&format!("{idx} = select i1 {over}, i64 {back}, i64 {sum}"));
+        }
+        TapeBounds::Checked | TapeBounds::Grow => {
+            let id = g.uniq;
+            g.uniq += 1;
+            g.inst(&format!("{idx} = add i64 {cur}, {delta}"));
+            // Unsigned, so negative indices compare as out of range too. A growable tape's
+            // range is shifted to start at 0 first.
+            if g.bounds == TapeBounds::Grow {
+                g.inst(&format!("%rel{id} = add i64 {idx}, {GROW_LIMIT}"));
+                g.inst(&format!(
+                    "%oob{id} = icmp uge i64 %rel{id}, {}",
+                    2 * GROW_LIMIT
+                ));
+            } else {
+                g.inst(&format!("%oob{id} = icmp uge i64 {idx}, {len}"));
+            }
+            g.inst(&format!(
+                "br i1 %oob{id}, label %bounds.fail.{id}, label %bounds.ok.{id}"
+            ));
+            g.label(&format!("bounds.fail.{id}"));
+            g.inst(&format!(
+                "call void @bf_bounds_fail(i64 {idx}, i64 {}, i64 {})",
+                span.line, span.col
+            ));
+            g.inst("unreachable");
+            g.label(&format!("bounds.ok.{id}"));
+        }
+    }
+    idx
+}
+
+fn add_cell(g: &mut Codegen, s: &str, delta: i64, span: Span) {
+    let (idx, p) = cell_at(g, 0, span);
+    let amount = wrap_to_cell(delta, g.cell_bits); // Cells wrap at their width
+    add_at(g, s, &idx, &p, &amount.to_string());
+}
+
+/// `*p += amount` with sanitizer hooks for cell `idx`
+fn add_at(g: &mut Codegen, s: &str, idx: &str, p: &str, amount: &str) {
+    let cell = g.cell();
+    let cell_p = g.ptr(&cell);
+    hook(g, s, "read", idx);
+    let v0 = g.fresh("v");
+    let v1 = g.fresh("v");
+    g.inst(&format!("{v0} = load {cell}, {cell_p} {p}"));
+    g.inst(&format!("{v1} = add {cell} {v0}, {amount}"));
+    hook(g, s, "write", idx);
+    g.inst(&format!("store {cell} {v1}, {cell_p} {p}"));
+}
+
+fn emit_mul_add(g: &mut Codegen, s: &str, targets: &[(i64, i64)], span: Span) {
+    let cell = g.cell();
+    let cell_p = g.ptr(&cell);
+    let (idx, p) = cell_at(g, 0, span);
+    hook(g, s, "read", &idx);
+    let v = g.fresh("v");
+    g.inst(&format!("{v} = load {cell}, {cell_p} {p}"));
+    // Skipped on a zero cell, see `NodeKind::MulAdd`
+    let id = g.uniq;
+    g.uniq += 1;
+    g.inst(&format!("%mnz{id} = icmp ne {cell} {v}, 0"));
+    g.inst(&format!(
+        "br i1 %mnz{id}, label %muladd.body.{id}, label %muladd.end.{id}"
+    ));
+    g.label(&format!("muladd.body.{id}"));
+    for (offset, factor) in targets {
+        let factor = wrap_to_cell(*factor, g.cell_bits); // Cells wrap at their width
+        let prod = g.fresh("prod");
+        g.inst(&format!("{prod} = mul {cell} {v}, {factor}"));
+        let (tidx, tp) = cell_at(g, *offset, span);
+        add_at(g, s, &tidx, &tp, &prod);
+    }
+    hook(g, s, "write", &idx);
+    g.inst(&format!("store {cell} 0, {cell_p} {p}"));
+    g.inst(&format!("br label %muladd.end.{id}"));
+    g.label(&format!("muladd.end.{id}"));
+}
+
+fn emit_scan(g: &mut Codegen, s: &str, step: i64, span: Span) {
+    let cell = g.cell();
+    let cell_p = g.ptr(&cell);
+    let i64_p = g.ptr("i64");
+    // memchr finds zero bytes, which are only zero cells when cells are bytes, and stops at
+    // the end of the tape
+    if step == 1 && !g.sanitize && g.cell_bits == 8 && g.bounds == TapeBounds::Unchecked {
+        let cur = g.fresh("idx");
+        let next = g.fresh("idx");
+        g.inst(&format!("{cur} = load i64, {i64_p} %ptr.slot"));
+        g.inst(&format!(
+            "{next} = call i64 @bf_scan_fwd({cell_p} %tape, i64 {cur})"
+        ));
+        g.inst(&format!("store i64 {next}, {i64_p} %ptr.slot"));
+        return;
+    }
+
+    let id = g.uniq;
+    g.uniq += 1;
+    g.inst(&format!("br label %scan.cond.{id}"));
+    g.label(&format!("scan.cond.{id}"));
+    let (idx, p) = cell_at(g, 0, span);
+    hook(g, s, "read", &idx);
+    g.inst(&format!("%sv{id} = load {cell}, {cell_p} {p}"));
+    g.inst(&format!("%snz{id} = icmp ne {cell} %sv{id}, 0"));
+    g.inst(&format!(
+        "br i1 %snz{id}, label %scan.step.{id}, label %scan.end.{id}"
+    ));
+    g.label(&format!("scan.step.{id}"));
+    move_ptr(g, step, span);
+    g.inst(&format!("br label %scan.cond.{id}"));
+    g.label(&format!("scan.end.{id}"));
+}
+
+fn emit_loop(g: &mut Codegen, s: &str, n: &Node, body: &[Node]) {
+    let cell = g.cell();
+    let cell_p = g.ptr(&cell);
     let id = g.uniq;
     g.uniq += 1;
     let l_cond = format!("loop.cond.{id}");
     let l_body = format!("loop.body.{id}");
     let l_end = format!("loop.end.{id}");
 
-    g.line(&format!("br label %{l_cond}"));
+    g.inst(&format!("br label %{l_cond}"));
     g.label(&l_cond);
-    g.line(&format!("%p{id} = call i8* @bf_gep_cell_ptr(%State* {s})"));
-    if g.sanitize {
-        g.line(&format!("call void @tsan_read(%State* {s})"));
-    }
-    g.line(&format!("%v{id} = load i8, i8* %p{id}"));
-    g.line(&format!("%nz{id} = icmp ne i8 %v{id}, 0"));
-    g.line(&format!("br i1 %nz{id}, label %{l_body}, label %{l_end}"));
+    let (idx, p) = cell_at(g, 0, n.span);
+    hook(g, s, "read", &idx);
+    g.inst(&format!("%v{id} = load {cell}, {cell_p} {p}"));
+    g.inst(&format!("%nz{id} = icmp ne {cell} %v{id}, 0"));
+    g.inst(&format!("br i1 %nz{id}, label %{l_body}, label %{l_end}"));
 
     g.label(&l_body);
     emit_nodes(g, s, body);
-    g.line(&format!("br label %{l_cond}"));
+    g.set_location(n.span);
+    g.inst(&format!("br label %{l_cond}"));
 
     g.label(&l_end);
 }
@@ -1,17 +1,155 @@
-use std::fmt::Write as _;
+use std::fmt::{self, Write as _};
 
-use crate::parser::Node;
+use crate::parser::{Node, NodeKind};
 
+mod chunks;
+mod debug;
 mod decl;
 mod emit;
 mod parallel;
 
-pub const TAPE_LEN: i64 = 30_000;
+use debug::DebugInfo;
+pub use debug::DebugSource;
+
+pub const DEFAULT_TAPE_LEN: i64 = 30_000;
 pub const MUTEX_STRIDE: i64 = 64;
 pub const LOCK_STACK_INIT: i64 = 16;
+pub const OUT_BUF_LEN: i64 = 4096;
+pub const CELL_BITS: [u32; 4] = [8, 16, 32, 64];
+
+/// A growable tape (`--tape-grow`) is allocated in zeroed chunks of `1 << GROW_CHUNK_BITS`
+/// cells that never move once created, so growth is safe while other threads use the tape
+pub const GROW_CHUNK_BITS: u32 = 12;
+/// Chunk directory size of a growable tape, half of it left of cell 0
+pub const GROW_CHUNKS: i64 = 1 << 16;
+/// A growable tape spans cells `-GROW_LIMIT..GROW_LIMIT`
+pub const GROW_LIMIT: i64 = (GROW_CHUNKS / 2) << GROW_CHUNK_BITS;
+
+/// `value` reduced to a `bits`-wide two's complement integer, as cell arithmetic wraps
+pub fn wrap_to_cell(value: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (value << shift) >> shift
+}
+
+/// How compiled programs buffer `.` output. Whatever the mode, pending output is flushed
+/// before `,` so prompts show before blocking on input, and when parallel branches join so
+/// their output is visible once they have all finished.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputBuffering {
+    /// Flush after every byte
+    #[default]
+    None,
+    /// Flush on newline, when the buffer fills, before input, at joins and at exit
+    Line,
+    /// Flush when the buffer fills, before input, at joins and at exit
+    Full,
+}
+
+impl OutputBuffering {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(OutputBuffering::None),
+            "line" => Some(OutputBuffering::Line),
+            "full" => Some(OutputBuffering::Full),
+            _ => None,
+        }
+    }
+}
+
+/// What `,` stores once input is exhausted. Every backend defaults to `Zero`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Eof {
+    /// Leave the cell as it was
+    Unchanged,
+    /// Store 0
+    #[default]
+    Zero,
+    /// Store -1, i.e. all ones at the cell width
+    MinusOne,
+}
+
+impl Eof {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unchanged" => Some(Eof::Unchanged),
+            "zero" => Some(Eof::Zero),
+            "minus-one" => Some(Eof::MinusOne),
+            _ => None,
+        }
+    }
+}
+
+/// What happens when the pointer moves off either end of the tape
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TapeBounds {
+    /// No checks: compiled code indexes outside the tape
+    #[default]
+    Unchecked,
+    /// Stop with the thread, pointer value and source position
+    Checked,
+    /// Continue from the other end of the tape
+    Wrap,
+    /// Extend the tape in that direction, up to `GROW_LIMIT` cells either side of cell 0, and
+    /// stop like `Checked` beyond that
+    Grow,
+}
+
+impl TapeBounds {
+    /// Whether leaving the valid range stops the program with a report
+    pub fn reports(self) -> bool {
+        matches!(self, TapeBounds::Checked | TapeBounds::Grow)
+    }
+}
+
+/// Report for a checked pointer leaving the tape, or a growable one passing its limit, shared
+/// by every backend so their messages match. Compiled backends pass printf conversions for the
+/// values only known at run time; thread 0 is the main thread and others are numbered in the
+/// order they start.
+pub fn bounds_message(
+    thread: impl fmt::Display,
+    cell: impl fmt::Display,
+    bounds: TapeBounds,
+    tape_len: i64,
+    span: (impl fmt::Display, impl fmt::Display),
+) -> String {
+    let (line, col) = span;
+    if bounds == TapeBounds::Grow {
+        let last = GROW_LIMIT - 1;
+        format!(
+            "error: tape limit reached: thread {thread} moved the pointer to cell {cell}, but a growable tape spans cells -{GROW_LIMIT} to {last}, at {line}:{col}"
+        )
+    } else {
+        format!(
+            "error: thread {thread} moved the pointer to cell {cell}, outside the {tape_len}-cell tape, at {line}:{col}"
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub sanitize: bool, // Instrument memory and sync operations for the runtime
+    pub debug_info: Option<DebugSource>, // Emit DWARF metadata pointing at this source file
+    pub output: OutputBuffering,
+    pub typed_pointers: bool, // Emit legacy `i8*`-style pointers instead of opaque `ptr`
+    pub tape_len: i64,        // Number of cells, shared with the sanitizer runtime at startup
+    pub cell_bits: u32,       // Width of a cell, one of `CELL_BITS`
+    pub bounds: TapeBounds,
+    pub eof: Eof,
+}
 
-pub fn generate_ir(nodes: &[Node], sanitize: bool) -> String {
-    let mut cg = Codegen::new(sanitize);
+/// Whether any lock, unlock, wait or notify appears in `nodes`, including inside loops and
+/// parallel branches. Backends skip all per-cell sync objects for programs that never use them.
+pub fn uses_sync(nodes: &[Node]) -> bool {
+    nodes.iter().any(|n| match &n.kind {
+        NodeKind::LockAcquire | NodeKind::LockRelease | NodeKind::Wait | NodeKind::Notify => true,
+        NodeKind::Loop(body) => uses_sync(body),
+        NodeKind::Parallel(branches) => branches.iter().any(|b| uses_sync(b)),
+        _ => false,
+    })
+}
+
+pub fn generate_ir(nodes: &[Node], opts: &Options) -> String {
+    let mut cg = Codegen::new(opts, uses_sync(nodes));
     cg.preamble(); // globals, %State, declarations, runtime helper definitions
     cg.defer_thunk("main", nodes); // Defer creation of thunk for main
     cg.define_main(); // Initialize @main then call @thunk_main
@@ -24,21 +162,40 @@ pub struct Codegen {
     indent: usize,
     pub uniq: usize,
     deferred: Vec<String>, // Function definitions deferred for later emission
+    allocas: Vec<String>,  // Allocas of the thunk being emitted, hoisted into its entry block
     pub sanitize: bool,    // Whether to generate code with sanitization checks
+    pub output: OutputBuffering,
+    typed_pointers: bool,
+    pub tape_len: i64,
+    pub cell_bits: u32,
+    pub bounds: TapeBounds,
+    pub eof: Eof,
+    pub sync: bool, // Whether the program locks or signals, so needs `@sync_dir`
+    debug: Option<DebugInfo>, // DWARF metadata, when debug info is requested
 }
 
 impl Codegen {
-    fn new(sanitize: bool) -> Self {
+    fn new(opts: &Options, sync: bool) -> Self {
         Self {
             out: String::with_capacity(32 * 1024),
             indent: 0,
             uniq: 0,
             deferred: Vec::new(),
-            sanitize,
+            allocas: Vec::new(),
+            sanitize: opts.sanitize,
+            output: opts.output,
+            typed_pointers: opts.typed_pointers,
+            tape_len: opts.tape_len,
+            cell_bits: opts.cell_bits,
+            bounds: opts.bounds,
+            eof: opts.eof,
+            sync,
+            debug: opts.debug_info.as_ref().map(DebugInfo::new),
         }
     }
 
-    pub fn finish(self) -> String {
+    pub fn finish(mut self) -> String {
+        self.debug_trailer();
         self.out
     }
 
@@ -48,6 +205,25 @@ impl Codegen {
         }
         let _ = writeln!(self.out, "{s}");
     }
+    /// Pointer type for `pointee`: `ptr` for opaque-pointer IR, `<pointee>*` for typed IR
+    pub fn ptr(&self, pointee: &str) -> String {
+        if self.typed_pointers {
+            format!("{pointee}*")
+        } else {
+            "ptr".to_string()
+        }
+    }
+
+    /// IR integer type of a tape cell
+    pub fn cell(&self) -> String {
+        format!("i{}", self.cell_bits)
+    }
+
+    /// Emit an instruction attributed to the current debug location
+    pub fn inst(&mut self, s: &str) {
+        let dbg = self.dbg();
+        self.line(&format!("{s}{dbg}"));
+    }
     pub fn label(&mut self, name: &str) {
         let _ = writeln!(self.out, "{name}:");
     }
@@ -57,6 +233,12 @@ impl Codegen {
         format!("%{prefix}{id}")
     }
 
+    /// Reserve `ty` on the stack of the current thunk. Allocas outside the entry block are
+    /// dynamic, so one inside a loop would grow the native stack every iteration.
+    pub fn entry_alloca(&mut self, name: &str, ty: &str) {
+        self.allocas.push(format!("{name} = alloca {ty}"));
+    }
+
     fn push_def(&mut self, def: String) {
         self.deferred.push(def);
     }
@@ -82,41 +264,66 @@ impl Codegen {
 
     /// Create a thunk from an arbitrary sequence of nodes and defer its emission
     pub fn defer_thunk(&mut self, name: &str, nodes: &[Node]) {
+        let st_p = self.ptr("%State");
+        let line = nodes.first().map_or(1, |n| n.span.line);
+        let (saved, dbg) = self.enter_subprogram(&format!("thunk_{name}"), line);
         let ir = self.with_temp_buffer(|this| {
             this.line(&format!(
-                "define internal void @thunk_{name}(%State* nocapture nonnull %S) {{"
+                "define internal void @thunk_{name}({st_p} nocapture nonnull %S){dbg} {{"
             ));
             this.indent += 1;
             this.label("entry");
-            emit::emit_nodes(this, "%S", nodes);
+            let saved_allocas = std::mem::take(&mut this.allocas);
+            let body = this.with_temp_buffer(|this| {
+                this.indent = 1;
+                emit::emit_prologue(this, "%S");
+                emit::emit_nodes(this, "%S", nodes);
+            });
+            for alloca in std::mem::replace(&mut this.allocas, saved_allocas) {
+                this.line(&alloca);
+            }
+            this.out.push_str(&body);
             this.line("ret void");
             this.indent -= 1;
             this.line("}");
             this.line("");
         });
+        self.leave_subprogram(saved);
         self.push_def(ir);
     }
 
     /// Defer generation of thread_start_* wrapper function
     pub fn defer_thread_start(&mut self, tname: &str) {
+        let st_p = self.ptr("%State");
+        let i8_p = self.ptr("i8");
+        let i64_p = self.ptr("i64");
         let ir = self.with_temp_buffer(|this| {
             this.line(&format!(
-                "define internal i8* @thread_start_{tname}(i8* %arg) nounwind {{"
+                "define internal {i8_p} @thread_start_{tname}({i8_p} %arg) nounwind {{"
             ));
             this.indent += 1;
-            this.line("%S = bitcast i8* %arg to %State*");
+            this.line(&format!("%S = bitcast {i8_p} %arg to {st_p}"));
             if this.sanitize {
                 // Post parent thread ID to TSAN
-                this.line("%fld_tid = getelementptr %State, %State* %S, i32 0, i32 6");
-                this.line("%tid_parent = load i64, i64* %fld_tid");
+                this.line(&format!(
+                    "%fld_tid = getelementptr %State, {st_p} %S, i32 0, i32 5"
+                ));
+                this.line(&format!("%tid_parent = load i64, {i64_p} %fld_tid"));
                 this.line("call void @tsan_fork(i64 %tid_parent)");
 
                 // Initialize thread ID if sanitization is enabled
                 this.line("%tid_self = call i64 @pthread_self()");
-                this.line("store i64 %tid_self, i64* %fld_tid");
+                this.line(&format!("store i64 %tid_self, {i64_p} %fld_tid"));
+            }
+            if this.bounds.reports() {
+                this.line(&format!(
+                    "%tcount = atomicrmw add {i64_p} @bf_thread_count, i64 1 seq_cst"
+                ));
+                this.line("%tnum = add i64 %tcount, 1");
+                this.line(&format!("store i64 %tnum, {i64_p} @bf_thread_id"));
             }
-            this.line(&format!("call void @thunk_{tname}(%State* %S)"));
-            this.line("ret i8* null");
+            this.line(&format!("call void @thunk_{tname}({st_p} %S)"));
+            this.line(&format!("ret {i8_p} null"));
             this.indent -= 1;
             this.line("}");
             this.line("");
@@ -125,21 +332,40 @@ impl Codegen {
     }
 
     fn preamble(&mut self) {
-        // Shared tape and mutex slot slab (memory allocated at program start)
-        self.line(&format!(
-            "@tape = internal global [{TAPE_LEN} x i8] zeroinitializer"
-        ));
-        self.line("@mutex_slab = internal global i8* null");
-        self.line("@cond_slab = internal global i8* null");
-        self.line("@cond_mtx_slab = internal global i8* null");
+        let tape_len = self.tape_len;
+        let i8_p = self.ptr("i8");
+        let i64_p = self.ptr("i64");
+        // Shared tape, and the chunk directories of lazily allocated memory
+        let cell = self.cell();
+        if self.bounds != TapeBounds::Grow {
+            self.line(&format!(
+                "@tape = internal global [{tape_len} x {cell}] zeroinitializer"
+            ));
+        }
+        chunks::declare_globals(self);
+        if self.bounds.reports() {
+            // Thread numbers for `bounds_message`
+            self.line("@bf_thread_count = internal global i64 0");
+            self.line("@bf_thread_id = internal thread_local global i64 0");
+        }
+        if self.output != OutputBuffering::None {
+            // Process-wide output buffer, guarded by @outmtx
+            self.line(&format!(
+                "@outbuf = internal global [{OUT_BUF_LEN} x i8] zeroinitializer"
+            ));
+            self.line("@outlen = internal global i64 0");
+            self.line(&format!(
+                "@outmtx = internal global [{MUTEX_STRIDE} x i8] zeroinitializer"
+            ));
+        }
         if self.sanitize {
-            self.line(
-                "%State = type { i8*, i64, i8*, i64*, i64, i64, i64 } ; (tape, ptr, slab, stack, sp, cap, tid)",
-            );
+            self.line(&format!(
+                "%State = type {{ {i8_p}, i64, {i64_p}, i64, i64, i64 }} ; (tape, ptr, stack, sp, cap, tid)",
+            ));
         } else {
-            self.line(
-                "%State = type { i8*, i64, i8*, i64*, i64, i64 } ; (tape, ptr, slab, stack, sp, cap)",
-            );
+            self.line(&format!(
+                "%State = type {{ {i8_p}, i64, {i64_p}, i64, i64 }} ; (tape, ptr, stack, sp, cap)",
+            ));
         }
         self.line("%timespec = type { i64, i64 } ; (tv_sec, tv_nsec)");
         self.line("");
@@ -149,95 +375,89 @@ impl Codegen {
     }
 
     fn define_main(&mut self) {
+        let tape_len = self.tape_len;
+        let tape_ty = format!("[{tape_len} x {}]", self.cell());
+        let tape_p = self.ptr(&tape_ty);
+        let mtx_p = self.ptr(&format!("[{MUTEX_STRIDE} x i8]"));
+        let st_p = self.ptr("%State");
+        let i8_pp = self.ptr(&self.ptr("i8"));
+        let i64_pp = self.ptr(&self.ptr("i64"));
+        let i8_p = self.ptr("i8");
+        let i64_p = self.ptr("i64");
         self.line("define i32 @main() {");
         self.indent += 1;
         self.label("entry");
-        // Allocate & initialize mutex_slab
-        self.line(&format!("%slab_bytes = mul i64 {TAPE_LEN}, {MUTEX_STRIDE}"));
-        self.line("%slab = call i8* @malloc(i64 %slab_bytes)");
-        self.line("store i8* %slab, i8** @mutex_slab");
-        // Allocate & initialize cond_slab and cond_mtx_slab
-        self.line(&format!("%cond_bytes = mul i64 {TAPE_LEN}, {MUTEX_STRIDE}"));
-        self.line("%cslab = call i8* @malloc(i64 %cond_bytes)");
-        self.line("store i8* %cslab, i8** @cond_slab");
-        self.line("%cmslab = call i8* @malloc(i64 %cond_bytes)");
-        self.line("store i8* %cmslab, i8** @cond_mtx_slab");
-        // pthread_mutex_init for every cell
-        self.line("%i = alloca i64");
-        self.line("store i64 0, i64* %i");
-        self.line("br label %init.loop");
-        self.label("init.loop");
-        self.line("%cur = load i64, i64* %i");
-        self.line(&format!("%cond = icmp slt i64 %cur, {TAPE_LEN}"));
-        self.line("br i1 %cond, label %init.body, label %init.end");
-        self.label("init.body");
-        self.line(&format!("%off = mul i64 %cur, {MUTEX_STRIDE}"));
-        self.line("%sl0 = load i8*, i8** @mutex_slab");
-        self.line("%slot = getelementptr i8, i8* %sl0, i64 %off");
-        self.line("call i32 @pthread_mutex_init(i8* %slot, i8* null)");
-        self.line("%cl0 = load i8*, i8** @cond_slab");
-        self.line("%cslot = getelementptr i8, i8* %cl0, i64 %off");
-        self.line("call i32 @pthread_cond_init(i8* %cslot, i8* null)");
-        self.line("%ml0 = load i8*, i8** @cond_mtx_slab");
-        self.line("%mslot2 = getelementptr i8, i8* %ml0, i64 %off");
-        self.line("call i32 @pthread_mutex_init(i8* %mslot2, i8* null)");
-        self.line("%cur1 = add i64 %cur, 1");
-        self.line("store i64 %cur1, i64* %i");
-        self.line("br label %init.loop");
-        self.label("init.end");
+        if self.sanitize {
+            // The runtime checks accesses against the tape length; 0 lets the tape grow
+            let len = if self.bounds == TapeBounds::Grow {
+                0
+            } else {
+                tape_len
+            };
+            self.line(&format!("call void @tsan_init(i64 {len})"));
+        }
+        if self.output != OutputBuffering::None {
+            self.line(&format!(
+                "%outmtx = getelementptr [{MUTEX_STRIDE} x i8], {mtx_p} @outmtx, i64 0, i64 0"
+            ));
+            self.line(&format!(
+                "call i32 @pthread_mutex_init({i8_p} %outmtx, {i8_p} null)"
+            ));
+        }
         // Allocate & initialize initial State
-        self.line("%st_end = getelementptr %State, %State* null, i32 1");
-        self.line("%st_bytes = ptrtoint %State* %st_end to i64");
-        self.line("%st = call i8* @malloc(i64 %st_bytes)");
-        self.line("%S = bitcast i8* %st to %State*");
         self.line(&format!(
-            "%base = getelementptr [{TAPE_LEN} x i8], [{TAPE_LEN} x i8]* @tape, i64 0, i64 0"
+            "%st_end = getelementptr %State, {st_p} null, i32 1"
         ));
+        self.line(&format!("%st_bytes = ptrtoint {st_p} %st_end to i64"));
+        self.line(&format!("%st = call {i8_p} @malloc(i64 %st_bytes)"));
+        self.line(&format!("%S = bitcast {i8_p} %st to {st_p}"));
+        if self.bounds == TapeBounds::Grow {
+            self.line(&format!("%base = bitcast {i8_p} null to {i8_p}"));
+        } else {
+            self.line(&format!("%base = bitcast {tape_p} @tape to {i8_p}"));
+        }
         let f0 = self.fresh("fld");
         self.line(&format!(
-            "{f0} = getelementptr %State, %State* %S, i32 0, i32 0"
+            "{f0} = getelementptr %State, {st_p} %S, i32 0, i32 0"
         ));
-        self.line(&format!("store i8* %base, i8** {f0}"));
+        self.line(&format!("store {i8_p} %base, {i8_pp} {f0}"));
         let f1 = self.fresh("fld");
         self.line(&format!(
-            "{f1} = getelementptr %State, %State* %S, i32 0, i32 1"
+            "{f1} = getelementptr %State, {st_p} %S, i32 0, i32 1"
         ));
-        self.line(&format!("store i64 0, i64* {f1}"));
-        self.line("%sl = load i8*, i8** @mutex_slab");
+        self.line(&format!("store i64 0, {i64_p} {f1}"));
+        self.line(&format!("%lsz = mul i64 {LOCK_STACK_INIT}, 8"));
+        self.line(&format!("%stk = call {i8_p} @malloc(i64 %lsz)"));
+        self.line(&format!("%stk64 = bitcast {i8_p} %stk to {i64_p}"));
         let f2 = self.fresh("fld");
         self.line(&format!(
-            "{f2} = getelementptr %State, %State* %S, i32 0, i32 2"
+            "{f2} = getelementptr %State, {st_p} %S, i32 0, i32 2"
         ));
-        self.line(&format!("store i8* %sl, i8** {f2}"));
-        self.line(&format!("%lsz = mul i64 {LOCK_STACK_INIT}, 8"));
-        self.line("%stk = call i8* @malloc(i64 %lsz)");
-        self.line("%stk64 = bitcast i8* %stk to i64*");
+        self.line(&format!("store {i64_p} %stk64, {i64_pp} {f2}"));
         let f3 = self.fresh("fld");
         self.line(&format!(
-            "{f3} = getelementptr %State, %State* %S, i32 0, i32 3"
+            "{f3} = getelementptr %State, {st_p} %S, i32 0, i32 3"
         ));
-        self.line(&format!("store i64* %stk64, i64** {f3}"));
+        self.line(&format!("store i64 0, {i64_p} {f3}"));
         let f4 = self.fresh("fld");
         self.line(&format!(
-            "{f4} = getelementptr %State, %State* %S, i32 0, i32 4"
-        ));
-        self.line(&format!("store i64 0, i64* {f4}"));
-        let f5 = self.fresh("fld");
-        self.line(&format!(
-            "{f5} = getelementptr %State, %State* %S, i32 0, i32 5"
+            "{f4} = getelementptr %State, {st_p} %S, i32 0, i32 4"
         ));
-        self.line(&format!("store i64 {LOCK_STACK_INIT}, i64* {f5}"));
+        self.line(&format!("store i64 {LOCK_STACK_INIT}, {i64_p} {f4}"));
         if self.sanitize {
             // Initialize thread ID if sanitization is enabled
             self.line("%tid = call i64 @pthread_self()");
-            let f6 = self.fresh("fld");
+            let f5 = self.fresh("fld");
             self.line(&format!(
-                "{f6} = getelementptr %State, %State* %S, i32 0, i32 6"
+                "{f5} = getelementptr %State, {st_p} %S, i32 0, i32 5"
             ));
-            self.line(&format!("store i64 %tid, i64* {f6}"));
+            self.line(&format!("store i64 %tid, {i64_p} {f5}"));
         }
         // Run top-level program
-        self.line("call void @thunk_main(%State* %S)");
+        self.line(&format!("call void @thunk_main({st_p} %S)"));
+        if self.output != OutputBuffering::None {
+            self.line("call void @bf_flush_out()");
+        }
         self.line("ret i32 0");
         self.indent -= 1;
         self.line("}");
@@ -0,0 +1,65 @@
+use std::fmt::Write as _;
+
+use crate::lexer::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// An error or warning pointing at a position in the source file
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            span,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            span,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Render as `<level>: <message>` followed by the offending source line and a caret
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let Span { line, col } = self.span;
+        let text = source.lines().nth(line - 1).unwrap_or("");
+        let gutter = " ".repeat(line.to_string().len());
+
+        // Keep tabs in the caret line so the caret lines up with the source text
+        let pad: String = text
+            .chars()
+            .take(col - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let mut out = String::new();
+        let level = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let _ = writeln!(out, "{level}: {}", self.message);
+        let _ = writeln!(out, "{gutter}--> {path}:{line}:{col}");
+        let _ = writeln!(out, "{gutter} |");
+        let _ = writeln!(out, "{line} | {text}");
+        let _ = write!(out, "{gutter} | {pad}^");
+        out
+    }
+}
@@ -0,0 +1,314 @@
+use std::iter::Peekable;
+use std::slice::Iter;
+
+use crate::lexer::{Comment, Span, Token, TokenKind};
+use crate::parser::{Node, NodeKind};
+
+/// Preferred maximum line width; runs of simple instructions are wrapped to fit
+const WIDTH: usize = 80;
+const INDENT: &str = "  ";
+
+/// Re-emit a parsed program in canonical layout.
+///
+/// Runs of simple instructions (and loops made only of them) share a line, other loops put
+/// their body on indented lines between `[` and `]`, and parallel blocks print each branch
+/// indented between `{` and `}` with `| ` opening every branch after the first. Comments are
+/// kept at their position in the instruction stream; any other non-instruction text is dropped.
+/// `tokens` must be the token stream `nodes` was parsed from, so closing brackets can be located.
+pub fn format(nodes: &[Node], tokens: &[Token], comments: &[Comment]) -> String {
+    let mut p = Printer {
+        out: String::new(),
+        line: String::new(),
+        indent: 0,
+        bar: false,
+        tokens,
+        comments: comments.iter().peekable(),
+    };
+    p.seq(nodes);
+    p.comments_before(None);
+    p.end_line();
+    p.out
+}
+
+struct Printer<'a> {
+    out: String,
+    line: String, // Current line, without indentation
+    indent: usize,
+    bar: bool, // Whether the next line opens a parallel branch with `| `
+    tokens: &'a [Token],
+    comments: Peekable<Iter<'a, Comment>>,
+}
+
+impl Printer<'_> {
+    fn end_line(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        let line = std::mem::take(&mut self.line);
+        self.emit_line(&line);
+    }
+
+    fn emit_line(&mut self, text: &str) {
+        if self.bar {
+            // `| ` takes the place of the branch indentation
+            self.bar = false;
+            self.out.push_str(&INDENT.repeat(self.indent - 1));
+            self.out.push_str("| ");
+        } else {
+            self.out.push_str(&INDENT.repeat(self.indent));
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn code(&mut self, text: &str) {
+        let used = self.indent * INDENT.len() + self.line.len();
+        if !self.line.is_empty() && used + text.len() > WIDTH {
+            self.end_line();
+        }
+        self.line.push_str(text);
+    }
+
+    /// Print every comment that starts before `limit` (all remaining ones if `None`)
+    fn comments_before(&mut self, limit: Option<Span>) {
+        while let Some(c) = self.comments.next_if(|c| limit.is_none_or(|l| c.span < l)) {
+            if c.trailing && !self.line.is_empty() {
+                self.line.push(' ');
+                self.line.push_str(&c.text);
+            } else {
+                self.end_line();
+                self.line.push_str(&c.text);
+            }
+            self.end_line();
+        }
+    }
+
+    fn has_comment_before(&mut self, limit: Span) -> bool {
+        self.comments.peek().is_some_and(|c| c.span < limit)
+    }
+
+    fn seq(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        self.comments_before(Some(node.span));
+        match &node.kind {
+            NodeKind::Loop(body) => {
+                let end = self.end_of(node);
+                if is_flat(body) && !self.has_comment_before(end) {
+                    self.code(&flat(node));
+                    return;
+                }
+                self.end_line();
+                self.emit_line("[");
+                self.indent += 1;
+                self.seq(body);
+                self.comments_before(Some(end));
+                self.end_line();
+                self.indent -= 1;
+                self.emit_line("]");
+            }
+            NodeKind::Parallel(branches) => {
+                self.end_line();
+                self.emit_line("{");
+                let mut pos = node.span;
+                for (i, branch) in branches.iter().enumerate() {
+                    if let Some(last) = branch.last() {
+                        pos = self.end_of(last);
+                    }
+                    let closer = self.closer_after(pos, &[TokenKind::ParSep, TokenKind::ParEnd]);
+                    self.indent += 1;
+                    self.bar = i > 0;
+                    self.seq(branch);
+                    self.comments_before(Some(closer));
+                    self.end_line();
+                    self.indent -= 1;
+                    if self.bar {
+                        // Empty branch
+                        self.bar = false;
+                        self.emit_line("|");
+                    }
+                    pos = closer;
+                }
+                self.emit_line("}");
+            }
+            _ => self.code(&flat(node)),
+        }
+    }
+
+    /// Span of the token that closes `node` (the node itself for single-token instructions)
+    fn end_of(&self, node: &Node) -> Span {
+        match &node.kind {
+            NodeKind::Loop(body) => {
+                let pos = body.last().map_or(node.span, |last| self.end_of(last));
+                self.closer_after(pos, &[TokenKind::LoopEnd])
+            }
+            NodeKind::Parallel(branches) => {
+                let mut pos = node.span;
+                for branch in branches {
+                    if let Some(last) = branch.last() {
+                        pos = self.end_of(last);
+                    }
+                    pos = self.closer_after(pos, &[TokenKind::ParSep, TokenKind::ParEnd]);
+                }
+                pos
+            }
+            _ => node.span,
+        }
+    }
+
+    fn closer_after(&self, pos: Span, kinds: &[TokenKind]) -> Span {
+        let start = self.tokens.partition_point(|t| t.span <= pos);
+        self.tokens[start..]
+            .iter()
+            .find(|t| kinds.contains(&t.kind))
+            .expect("closing token of a parsed construct")
+            .span
+    }
+}
+
+/// Whether a sequence can be printed inline: no parallel blocks anywhere inside
+fn is_flat(nodes: &[Node]) -> bool {
+    nodes.iter().all(|n| match &n.kind {
+        NodeKind::Loop(body) => is_flat(body),
+        NodeKind::Parallel(_) => false,
+        _ => true,
+    })
+}
+
+fn flat(node: &Node) -> String {
+    match &node.kind {
+        NodeKind::IncPtr => ">".to_string(),
+        NodeKind::DecPtr => "<".to_string(),
+        NodeKind::IncCell => "+".to_string(),
+        NodeKind::DecCell => "-".to_string(),
+        NodeKind::Output => ".".to_string(),
+        NodeKind::Input => ",".to_string(),
+        NodeKind::LockAcquire => "(".to_string(),
+        NodeKind::LockRelease => ")".to_string(),
+        NodeKind::Sleep(count) => "~".repeat(*count),
+        NodeKind::Wait => "^".to_string(),
+        NodeKind::Notify => "v".to_string(),
+        NodeKind::Add(n) => (if *n < 0 { "-" } else { "+" }).repeat(n.unsigned_abs() as usize),
+        NodeKind::Move(n) => moves(*n),
+        NodeKind::Clear => "[-]".to_string(),
+        NodeKind::Scan(step) => format!("[{}]", moves(*step)),
+        NodeKind::MulAdd(targets) => {
+            let mut s = String::from("[-");
+            let mut at = 0;
+            for (offset, factor) in targets {
+                s.push_str(&moves(offset - at));
+                s.push_str(
+                    &(if *factor < 0 { "-" } else { "+" }).repeat(factor.unsigned_abs() as usize),
+                );
+                at = *offset;
+            }
+            s.push_str(&moves(-at));
+            s.push(']');
+            s
+        }
+        NodeKind::Loop(body) => format!("[{}]", body.iter().map(flat).collect::<String>()),
+        NodeKind::Parallel(_) => unreachable!("parallel blocks are never flat"),
+    }
+}
+
+fn moves(n: i64) -> String {
+    (if n < 0 { "<" } else { ">" }).repeat(n.unsigned_abs() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use crate::lexer::{Dialect, lex};
+    use crate::opt::tests::shape;
+    use crate::parser::parse;
+
+    const BRAINFORK: &[&str] = &[
+        "++>+<[->+<]>.",
+        "; setup\n+++ ; three\n[>{+|-}<-] /* block\ncomment */ .",
+        "{(+)|[(>-<)]|}{|}~~^v",
+        "+[>{(.)|{,|[-]}}<-]",
+        // Longer than `WIDTH`, so it wraps
+        "+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>",
+    ];
+    const BRAINFUCK: &[&str] = &[
+        "Print one: ++++++[>++++++++<-]>+.",
+        "; copy\n,[->+>+<<] ; done\n>>[-<<+>>]<<.",
+        "{not a block} (nor a lock) [-]",
+    ];
+
+    fn cases() -> impl Iterator<Item = (Dialect, &'static str)> {
+        let fork = BRAINFORK.iter().map(|src| (Dialect::Brainfork, *src));
+        fork.chain(BRAINFUCK.iter().map(|src| (Dialect::Brainfuck, *src)))
+    }
+
+    fn fmt(src: &str, dialect: Dialect) -> String {
+        let (tokens, comments) = lex(src, dialect).unwrap();
+        format(&parse(&tokens).unwrap(), &tokens, &comments)
+    }
+
+    fn tree(src: &str, dialect: Dialect) -> String {
+        shape(&parse(&lex(src, dialect).unwrap().0).unwrap())
+    }
+
+    fn comments(src: &str, dialect: Dialect) -> Vec<String> {
+        let (_, comments) = lex(src, dialect).unwrap();
+        comments.into_iter().map(|c| c.text).collect()
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for (dialect, src) in cases() {
+            let once = fmt(src, dialect);
+            assert_eq!(fmt(&once, dialect), once, "{src:?}");
+        }
+    }
+
+    #[test]
+    fn formatting_keeps_the_tree() {
+        for (dialect, src) in cases() {
+            assert_eq!(
+                tree(&fmt(src, dialect), dialect),
+                tree(src, dialect),
+                "{src:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn formatting_keeps_comments() {
+        for (dialect, src) in cases() {
+            let expected = comments(src, dialect);
+            assert_eq!(comments(&fmt(src, dialect), dialect), expected, "{src:?}");
+        }
+        assert_eq!(
+            fmt("; copy\n,[->+<] ; done\n", Dialect::Brainfuck),
+            "; copy\n,[->+<] ; done\n"
+        );
+    }
+
+    #[test]
+    fn indents_nested_parallel_blocks_and_locks() {
+        assert_eq!(
+            fmt("+[>{(.)|{,|[-]}|}<-]", Dialect::Brainfork),
+            "\
++
+[
+  >
+  {
+    (.)
+  | {
+      ,
+    | [-]
+    }
+  |
+  }
+  <-
+]
+"
+        );
+    }
+}
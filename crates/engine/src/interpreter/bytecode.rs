@@ -0,0 +1,303 @@
+use std::ops::Range;
+
+use crate::codegen::{Eof, TapeBounds};
+use crate::lexer::Span;
+use crate::opt::lower_loop;
+use crate::parser::{Node, NodeKind};
+
+/// One bytecode instruction. Pointer moves are two's complement `usize`s, pre-reduced modulo
+/// the tape length under `--tape-wrap`, and cell amounts are two's complement `u64`s that wrap
+/// to the cell width when stored, so the dispatch loop never has to normalize them.
+#[derive(Debug, Clone)]
+pub enum Op {
+    Move(usize),
+    Add(u64),
+    Clear,
+    MulAdd(Range<u32>), // Slice of `Program::targets`
+    Scan(usize),
+    /// Run the scan or multiply-add loop that follows in one go, leaving its cell zero so the
+    /// loop is skipped, when every cell a pass over its body visits is on the tape. Otherwise
+    /// the loop runs, and fails, step by step. Index into `Program::shortcuts`.
+    ScanShortcut(u32),
+    MulAddShortcut(u32),
+    Output,
+    Input,
+    /// Loop head: skip past the matching `JumpIfNonZero` when the cell is zero
+    JumpIfZero(usize),
+    /// Loop tail: back to the first instruction of the body while the cell is non-zero
+    JumpIfNonZero(usize),
+    Parallel(Range<u32>), // Slice of `Program::branches`
+    LockAcquire,
+    LockRelease(Box<Span>), // Boxed to keep `Op` at 16 bytes
+    Sleep(usize),
+    Wait,
+    Notify,
+    /// End of a thread body
+    Halt,
+}
+
+/// A whole program as one flat instruction array: the top level starts at 0, and every
+/// parallel branch is a `Halt`-terminated body elsewhere in `ops`.
+///
+/// Adjacent cell additions become one `Add`, and adjacent pointer moves in the same direction
+/// one `Move`. A run in one direction is out of bounds exactly when its last step is, so the
+/// dispatch loop checks a `Move` once and only replays its steps to report the failing one.
+#[derive(Debug)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    pub targets: Vec<(usize, u64)>, // Multiply-add (pointer offset, factor) pairs
+    pub branches: Vec<usize>,       // Entry point of every parallel branch
+    pub steps: Vec<Step>,           // Every op that moves the pointer, by pc
+    pub shortcuts: Vec<Shortcut>,
+    pub tape_len: usize,
+    pub bounds: TapeBounds, // `Unchecked` runs like `Checked`
+    pub eof: Eof,           // What `Input` stores at end of input
+    pub threaded: bool,     // Whether any `Parallel` runs, so cells can be shared
+}
+
+/// The net effect of a loop that a shortcut op runs at once
+#[derive(Debug)]
+pub struct Shortcut {
+    pub step: usize,         // Distance a scan moves per pass
+    pub targets: Range<u32>, // Multiply-add targets, in `Program::targets`
+    pub lo: usize,           // Lowest and highest offset a pass over the body visits,
+    pub hi: usize,           // both two's complement
+}
+
+/// One source instruction behind a pointer-moving op; a coalesced `Move` has one per instruction
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    pub pc: usize,
+    pub span: Span,
+    pub delta: i64, // Distance moved; 0 for multiply-adds and scans
+}
+
+impl Program {
+    /// Source instructions behind the pointer-moving op at `pc`, in order
+    pub fn steps(&self, pc: usize) -> &[Step] {
+        let start = self.steps.partition_point(|s| s.pc < pc);
+        let end = self.steps.partition_point(|s| s.pc <= pc);
+        &self.steps[start..end]
+    }
+}
+
+pub fn compile(nodes: &[Node], tape_len: usize, bounds: TapeBounds, eof: Eof) -> Program {
+    let mut c = Compiler {
+        prog: Program {
+            ops: Vec::new(),
+            targets: Vec::new(),
+            branches: Vec::new(),
+            steps: Vec::new(),
+            shortcuts: Vec::new(),
+            tape_len,
+            bounds,
+            eof,
+            threaded: forks(nodes),
+        },
+        pending: Vec::new(),
+        moved: None,
+    };
+    c.body(nodes);
+    // Branch bodies are laid out after the code that spawns them
+    while let Some((slot, nodes)) = c.pending.pop() {
+        c.prog.branches[slot] = c.prog.ops.len();
+        c.body(nodes);
+    }
+    c.prog
+}
+
+/// Whether `nodes` start threads anywhere
+fn forks(nodes: &[Node]) -> bool {
+    nodes.iter().any(|n| match &n.kind {
+        NodeKind::Parallel(_) => true,
+        NodeKind::Loop(body) => forks(body),
+        _ => false,
+    })
+}
+
+struct Compiler<'a> {
+    prog: Program,
+    pending: Vec<(usize, &'a [Node])>, // Branches still to be compiled, by `branches` slot
+    moved: Option<i64>,                // Total distance of the `Move` ending `ops`, unreduced
+}
+
+impl<'a> Compiler<'a> {
+    fn body(&mut self, nodes: &'a [Node]) {
+        self.seq(nodes);
+        self.push(Op::Halt);
+    }
+
+    fn push(&mut self, op: Op) {
+        self.moved = None;
+        self.prog.ops.push(op);
+    }
+
+    /// Add `n` to the current cell, extending an `Add` that ends `ops`. Jump targets always
+    /// follow a jump or `Halt`, so the previous op is never a different entry point.
+    fn add(&mut self, n: u64) {
+        match self.prog.ops.last_mut() {
+            Some(Op::Add(total)) => {
+                *total = total.wrapping_add(n);
+                if *total == 0 {
+                    self.prog.ops.pop();
+                }
+            }
+            _ => self.push(Op::Add(n)),
+        }
+    }
+
+    /// Move by `delta`, extending a `Move` in the same direction that ends `ops`
+    fn step(&mut self, delta: i64, span: Span) {
+        let pc = match self.moved {
+            Some(total) if (total < 0) == (delta < 0) => {
+                let total = total + delta;
+                let pc = self.prog.ops.len() - 1;
+                self.prog.ops[pc] = Op::Move(self.offset(total));
+                self.moved = Some(total);
+                pc
+            }
+            _ => {
+                self.push(Op::Move(self.offset(delta)));
+                self.moved = Some(delta);
+                self.prog.ops.len() - 1
+            }
+        };
+        self.prog.steps.push(Step { pc, span, delta });
+    }
+
+    /// Put a shortcut in front of a loop whose body amounts to a clear, scan or multiply-add, so
+    /// -O0 code runs these idioms about as fast as the optimizer's ones. A racing thread could
+    /// tell a shortcut from the loop, so programs with parallel blocks never get one.
+    fn shortcut(&mut self, body: &[Node]) {
+        let Some(kind) = lower_loop(body) else {
+            return;
+        };
+        let (mut at, mut lo, mut hi) = (0i64, 0i64, 0i64);
+        for node in body {
+            at += match node.kind {
+                NodeKind::IncPtr => 1,
+                NodeKind::DecPtr => -1,
+                NodeKind::Move(n) => n,
+                _ => 0,
+            };
+            lo = lo.min(at);
+            hi = hi.max(at);
+        }
+        let (lo, hi) = (lo as usize, hi as usize);
+        let idx = self.prog.shortcuts.len() as u32;
+        let op = match kind {
+            NodeKind::Clear => Op::Clear,
+            NodeKind::Scan(step) => {
+                let step = self.offset(step);
+                self.prog.shortcuts.push(Shortcut {
+                    step,
+                    targets: 0..0,
+                    lo,
+                    hi,
+                });
+                Op::ScanShortcut(idx)
+            }
+            NodeKind::MulAdd(targets) => {
+                let targets = self.targets(&targets);
+                self.prog.shortcuts.push(Shortcut {
+                    step: 0,
+                    targets,
+                    lo,
+                    hi,
+                });
+                Op::MulAddShortcut(idx)
+            }
+            _ => unreachable!(),
+        };
+        self.push(op);
+    }
+
+    /// Append multiply-add targets to `Program::targets`
+    fn targets(&mut self, targets: &[(i64, i64)]) -> Range<u32> {
+        let start = self.prog.targets.len();
+        for (offset, factor) in targets {
+            self.prog
+                .targets
+                .push((self.offset(*offset), *factor as u64));
+        }
+        start as u32..self.prog.targets.len() as u32
+    }
+
+    /// Offset `delta` as a forward distance around the tape when wrapping, otherwise as a
+    /// two's complement offset whose out-of-range sums the interpreter reports
+    fn offset(&self, delta: i64) -> usize {
+        if self.prog.bounds == TapeBounds::Wrap {
+            delta.rem_euclid(self.prog.tape_len as i64) as usize
+        } else {
+            delta as usize
+        }
+    }
+
+    fn seq(&mut self, nodes: &'a [Node]) {
+        for node in nodes {
+            if matches!(node.kind, NodeKind::MulAdd(_) | NodeKind::Scan(_)) {
+                self.prog.steps.push(Step {
+                    pc: self.prog.ops.len(),
+                    span: node.span,
+                    delta: 0,
+                });
+            }
+            let op = match &node.kind {
+                NodeKind::IncPtr => {
+                    self.step(1, node.span);
+                    continue;
+                }
+                NodeKind::DecPtr => {
+                    self.step(-1, node.span);
+                    continue;
+                }
+                NodeKind::Move(n) => {
+                    self.step(*n, node.span);
+                    continue;
+                }
+                NodeKind::IncCell => {
+                    self.add(1);
+                    continue;
+                }
+                NodeKind::DecCell => {
+                    self.add(u64::MAX);
+                    continue;
+                }
+                NodeKind::Add(n) => {
+                    self.add(*n as u64);
+                    continue;
+                }
+                NodeKind::Clear => Op::Clear,
+                NodeKind::MulAdd(targets) => Op::MulAdd(self.targets(targets)),
+                NodeKind::Scan(step) => Op::Scan(self.offset(*step)),
+                NodeKind::Output => Op::Output,
+                NodeKind::Input => Op::Input,
+                NodeKind::Loop(body) => {
+                    if !self.prog.threaded {
+                        self.shortcut(body);
+                    }
+                    let head = self.prog.ops.len();
+                    self.push(Op::JumpIfZero(0));
+                    self.seq(body);
+                    self.push(Op::JumpIfNonZero(head + 1));
+                    self.prog.ops[head] = Op::JumpIfZero(self.prog.ops.len());
+                    continue;
+                }
+                NodeKind::Parallel(branches) => {
+                    let start = self.prog.branches.len();
+                    for branch in branches {
+                        self.pending.push((self.prog.branches.len(), branch));
+                        self.prog.branches.push(0);
+                    }
+                    Op::Parallel(start as u32..self.prog.branches.len() as u32)
+                }
+                NodeKind::LockAcquire => Op::LockAcquire,
+                NodeKind::LockRelease => Op::LockRelease(Box::new(node.span)),
+                NodeKind::Sleep(count) => Op::Sleep(*count),
+                NodeKind::Wait => Op::Wait,
+                NodeKind::Notify => Op::Notify,
+            };
+            self.push(op);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicU8, AtomicU16, AtomicU32, AtomicU64, Ordering};
+
+/// Atomic storage for one tape cell. Values travel as `u64`: loads zero-extend and stores keep
+/// only the cell's own width, so wrapping `u64` arithmetic wraps exactly like the cell does.
+pub trait Cell: Default + Send + Sync + 'static {
+    fn get(&self) -> u64;
+    fn set(&self, value: u64);
+    /// Atomically add `n`, so racing `+`/`-` never lose an update
+    fn add(&self, n: u64);
+}
+
+macro_rules! impl_cell {
+    ($($atomic:ty => $int:ty),*) => {$(
+        impl Cell for $atomic {
+            fn get(&self) -> u64 {
+                self.load(Ordering::Relaxed) as u64
+            }
+
+            fn set(&self, value: u64) {
+                self.store(value as $int, Ordering::Relaxed);
+            }
+
+            fn add(&self, n: u64) {
+                self.fetch_add(n as $int, Ordering::Relaxed);
+            }
+        }
+    )*};
+}
+
+impl_cell!(AtomicU8 => u8, AtomicU16 => u16, AtomicU32 => u32, AtomicU64 => u64);
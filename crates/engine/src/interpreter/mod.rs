@@ -0,0 +1,362 @@
+use std::io::{Read, Result, Write};
+use std::sync::atomic::{AtomicU8, AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{process, thread};
+
+use crate::codegen::{Eof, TapeBounds, bounds_message};
+use crate::parser::Node;
+
+mod bytecode;
+mod cell;
+mod tape;
+
+use bytecode::{Op, Program, Shortcut};
+use cell::Cell;
+use tape::{Fixed, Growable, Tape};
+
+pub trait RBound = Read + Send + 'static;
+pub trait WBound = Write + Send + 'static;
+
+// Cells are relaxed atomics of the configured width, and `+`/`-` are atomic read-modify-writes,
+// so racing branches never lose an update. Ordering between threads comes from the lock flags
+// (SeqCst), from each cell's signal mutex and from thread spawn and join.
+//
+// Moving off either end of the tape stops the program with a report, unless `--tape-wrap`
+// makes the tape circular or `--tape-grow` extends it.
+
+pub struct Interpreter<R: RBound, W: WBound> {
+    input: Arc<Mutex<R>>,
+    output: Arc<Mutex<W>>,
+    tape_len: usize,
+    cell_bits: u32,
+    bounds: TapeBounds,
+    eof: Eof,
+}
+
+impl<R: RBound, W: WBound> Interpreter<R, W> {
+    pub fn new(
+        input: R,
+        output: W,
+        tape_len: usize,
+        cell_bits: u32,
+        bounds: TapeBounds,
+        eof: Eof,
+    ) -> Self {
+        Interpreter {
+            input: Arc::new(Mutex::new(input)),
+            output: Arc::new(Mutex::new(output)),
+            tape_len,
+            cell_bits,
+            bounds,
+            eof,
+        }
+    }
+
+    pub fn run(&self, nodes: &[Node]) -> Result<()> {
+        match self.cell_bits {
+            8 => self.run_with::<AtomicU8>(nodes),
+            16 => self.run_with::<AtomicU16>(nodes),
+            32 => self.run_with::<AtomicU32>(nodes),
+            _ => self.run_with::<AtomicU64>(nodes),
+        }
+    }
+
+    /// Run on a tape of `C` cells; the dispatch loop is monomorphized per width and tape kind
+    fn run_with<C: Cell>(&self, nodes: &[Node]) -> Result<()> {
+        let program = Arc::new(bytecode::compile(
+            nodes,
+            self.tape_len,
+            self.bounds,
+            self.eof,
+        ));
+        if self.bounds == TapeBounds::Grow {
+            self.run_on(program, Growable::<C>::new())
+        } else {
+            self.run_on(program, Fixed::<C>::new(self.tape_len))
+        }
+    }
+
+    fn run_on<T: Tape>(&self, program: Arc<Program>, tape: T) -> Result<()> {
+        ThreadState::<R, W, T>::new(
+            program,
+            Arc::new(tape),
+            self.input.clone(),
+            self.output.clone(),
+            0,
+            Arc::new(AtomicUsize::new(0)),
+        )
+        .run_from(0);
+        Ok(())
+    }
+}
+
+struct ThreadState<R: RBound, W: WBound, T: Tape> {
+    program: Arc<Program>,
+    tape: Arc<T>,
+    input: Arc<Mutex<R>>,
+    output: Arc<Mutex<W>>,
+    ptr: usize,
+    lock_stack: Vec<usize>,
+    threads: Arc<AtomicUsize>, // Number of threads started so far, for numbering new ones
+    id: usize,                 // 0 for the main thread
+}
+
+impl<R: RBound, W: WBound, T: Tape> ThreadState<R, W, T> {
+    fn new(
+        program: Arc<Program>,
+        tape: Arc<T>,
+        input: Arc<Mutex<R>>,
+        output: Arc<Mutex<W>>,
+        ptr: usize,
+        threads: Arc<AtomicUsize>,
+    ) -> Self {
+        let id = threads.fetch_add(1, Ordering::Relaxed);
+        ThreadState {
+            program,
+            tape,
+            input,
+            output,
+            ptr,
+            lock_stack: Vec::new(),
+            threads,
+            id,
+        }
+    }
+
+    /// Run the main thread from `pc`, on a tape only it can touch unless the program forks
+    fn run_from(&mut self, pc: usize) {
+        if self.program.threaded {
+            self.run::<true>(pc);
+        } else {
+            self.run::<false>(pc);
+        }
+    }
+
+    /// Execute from `pc` until the `Halt` that ends this thread body. `SHARED` is whether
+    /// other threads may touch the tape, which only programs with parallel blocks allow.
+    fn run<const SHARED: bool>(&mut self, mut pc: usize) {
+        let program = self.program.clone();
+        let ops = &program.ops[..];
+        let tape = self.tape.clone();
+        let tape = &*tape;
+        let tape_len = program.tape_len;
+        let wrap = program.bounds == TapeBounds::Wrap;
+        let id = self.id;
+        let mut ptr = self.ptr;
+
+        // Index `delta` cells past `ptr`, or stop the program with a report for the op at `pc`.
+        // A wrapping `delta` is already reduced modulo the tape length, so one lap back is
+        // enough.
+        let offset = |ptr: usize, delta: usize, pc: usize| {
+            let idx = ptr.wrapping_add(delta);
+            if tape.contains(idx) {
+                idx
+            } else if wrap {
+                idx - tape_len
+            } else {
+                out_of_bounds(&program, tape, id, ptr, idx, pc)
+            }
+        };
+        // SAFETY: `ptr` and every index from `offset` passed `contains`
+        let cell = |idx: usize| unsafe { tape.cell(idx) };
+        // Whether every cell a pass over a shortcut loop's body visits is on the tape
+        let fits = |ptr: usize, s: &Shortcut| {
+            wrap || tape.contains(ptr.wrapping_add(s.lo)) && tape.contains(ptr.wrapping_add(s.hi))
+        };
+
+        loop {
+            match &ops[pc] {
+                Op::Move(n) => {
+                    ptr = offset(ptr, *n, pc);
+                }
+                Op::Add(n) => {
+                    let c = cell(ptr);
+                    if SHARED {
+                        c.add(*n);
+                    } else {
+                        c.set(c.get().wrapping_add(*n));
+                    }
+                }
+                Op::Clear => {
+                    cell(ptr).set(0);
+                }
+                Op::MulAdd(targets) => {
+                    let v = cell(ptr).get();
+                    if v != 0 {
+                        for (delta, factor) in
+                            &program.targets[targets.start as usize..targets.end as usize]
+                        {
+                            let c = cell(offset(ptr, *delta, pc));
+                            if SHARED {
+                                c.add(v.wrapping_mul(*factor));
+                            } else {
+                                c.set(c.get().wrapping_add(v.wrapping_mul(*factor)));
+                            }
+                        }
+                        cell(ptr).set(0);
+                    }
+                }
+                Op::Scan(step) => {
+                    while cell(ptr).get() != 0 {
+                        ptr = offset(ptr, *step, pc);
+                    }
+                }
+                Op::ScanShortcut(i) => {
+                    let s = &program.shortcuts[*i as usize];
+                    while cell(ptr).get() != 0 && fits(ptr, s) {
+                        ptr = offset(ptr, s.step, pc);
+                    }
+                }
+                Op::MulAddShortcut(i) => {
+                    let s = &program.shortcuts[*i as usize];
+                    let v = cell(ptr).get();
+                    if v != 0 && fits(ptr, s) {
+                        for (delta, factor) in
+                            &program.targets[s.targets.start as usize..s.targets.end as usize]
+                        {
+                            let c = cell(offset(ptr, *delta, pc));
+                            c.set(c.get().wrapping_add(v.wrapping_mul(*factor)));
+                        }
+                        cell(ptr).set(0);
+                    }
+                }
+                Op::JumpIfZero(target) => {
+                    if cell(ptr).get() == 0 {
+                        pc = *target;
+                        continue;
+                    }
+                }
+                Op::JumpIfNonZero(target) => {
+                    if cell(ptr).get() != 0 {
+                        pc = *target;
+                        continue;
+                    }
+                }
+                Op::Output
+                | Op::Input
+                | Op::Parallel(_)
+                | Op::LockAcquire
+                | Op::LockRelease(_)
+                | Op::Sleep(_)
+                | Op::Wait
+                | Op::Notify => self.effect(&ops[pc], ptr),
+                Op::Halt => {
+                    self.ptr = ptr;
+                    return;
+                }
+            }
+            pc += 1;
+        }
+    }
+
+    /// Run an op that does I/O, sleeps or deals with other threads at cell `ptr`. These are out
+    /// of line so the dispatch loop keeps its state in registers.
+    #[inline(never)]
+    fn effect(&mut self, op: &Op, ptr: usize) {
+        match op {
+            Op::Output => {
+                // SAFETY: the dispatch loop only passes a `ptr` that `contains` accepted
+                let byte = unsafe { self.tape.cell(ptr) }.get() as u8; // Only the low byte is written
+                let mut out = self.output.lock().unwrap();
+                out.write_all(&[byte]).unwrap();
+                out.flush().unwrap();
+            }
+            Op::Input => {
+                // SAFETY: as for `Output`
+                let cell = unsafe { self.tape.cell(ptr) };
+                let mut buf = [0];
+                let mut inp = self.input.lock().unwrap();
+                match inp.read_exact(&mut buf) {
+                    Ok(()) => cell.set(buf[0].into()),
+                    Err(_) => match self.program.eof {
+                        Eof::Unchanged => {}
+                        Eof::Zero => cell.set(0),
+                        Eof::MinusOne => cell.set(u64::MAX),
+                    },
+                }
+            }
+            Op::Parallel(branches) => {
+                // Every branch starts at the parent's cell, like a copied `%State`
+                let mut handles = Vec::new();
+                for &entry in &self.program.branches[branches.start as usize..branches.end as usize]
+                {
+                    let mut child = ThreadState::new(
+                        self.program.clone(),
+                        self.tape.clone(),
+                        self.input.clone(),
+                        self.output.clone(),
+                        ptr,
+                        self.threads.clone(),
+                    );
+                    handles.push(thread::spawn(move || child.run::<true>(entry)));
+                }
+                for h in handles {
+                    h.join().unwrap();
+                }
+            }
+            Op::LockAcquire => {
+                while self
+                    .tape
+                    .lock(ptr)
+                    .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    thread::yield_now();
+                }
+                self.lock_stack.push(ptr);
+            }
+            Op::LockRelease(span) => {
+                if let Some(idx) = self.lock_stack.pop() {
+                    self.tape.lock(idx).store(false, Ordering::SeqCst);
+                } else {
+                    panic!("{span}: LockRelease without matching LockAcquire");
+                }
+            }
+            Op::Sleep(count) => {
+                let dur = Duration::from_millis(100 * (*count as u64));
+                thread::sleep(dur);
+            }
+            Op::Wait => self.tape.signal(ptr).wait(),
+            Op::Notify => self.tape.signal(ptr).notify(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Report the op at `pc`, run from `ptr` in thread `id`, leaving the tape at `idx` (two's
+/// complement, so left of the tape is negative), then stop the program. A coalesced `Move` is
+/// replayed one source instruction at a time to report the step that left.
+#[cold]
+fn out_of_bounds<T: Tape>(
+    program: &Program,
+    tape: &T,
+    id: usize,
+    ptr: usize,
+    mut idx: usize,
+    pc: usize,
+) -> ! {
+    let steps = program.steps(pc);
+    let mut span = steps[0].span;
+    if let Op::Move(_) = program.ops[pc] {
+        idx = ptr;
+        for step in steps {
+            idx = idx.wrapping_add(step.delta as usize);
+            span = step.span;
+            if !tape.contains(idx) {
+                break;
+            }
+        }
+    }
+    eprintln!(
+        "{}",
+        bounds_message(
+            id,
+            idx as isize,
+            program.bounds,
+            program.tape_len as i64,
+            (span.line, span.col)
+        )
+    );
+    process::exit(1);
+}
@@ -0,0 +1,136 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::{Condvar, Mutex, OnceLock};
+
+use super::cell::Cell;
+use crate::codegen::{GROW_CHUNK_BITS, GROW_CHUNKS, GROW_LIMIT};
+
+/// Cells, lock flags and signals of the tape. Indices are two's complement `usize`s, and the
+/// dispatch loop only passes ones `contains` accepted.
+pub trait Tape: Send + Sync + 'static {
+    type Cell: Cell;
+
+    fn contains(&self, idx: usize) -> bool;
+    /// # Safety
+    /// `contains(idx)` must hold; the cell is read without a second bounds check
+    unsafe fn cell(&self, idx: usize) -> &Self::Cell;
+    fn lock(&self, idx: usize) -> &AtomicBool;
+    fn signal(&self, idx: usize) -> &Signal;
+}
+
+/// A cell's condition variable and the mutex it is waited on under, matching `bf_wait` and
+/// `bf_notify`: a notify wakes every thread already waiting and is lost if none is
+#[derive(Default)]
+pub struct Signal {
+    mutex: Mutex<()>,
+    cond: Condvar,
+}
+
+impl Signal {
+    pub fn wait(&self) {
+        let guard = self.mutex.lock().unwrap();
+        drop(self.cond.wait(guard).unwrap());
+    }
+
+    pub fn notify(&self) {
+        let _guard = self.mutex.lock().unwrap();
+        self.cond.notify_all();
+    }
+}
+
+/// A tape of `len` cells starting at 0
+pub struct Fixed<C> {
+    cells: Box<[C]>,
+    locks: Box<[AtomicBool]>,
+    signals: Box<[Signal]>,
+}
+
+impl<C: Cell> Fixed<C> {
+    pub fn new(len: usize) -> Self {
+        Fixed {
+            cells: (0..len).map(|_| C::default()).collect(),
+            locks: (0..len).map(|_| AtomicBool::default()).collect(),
+            signals: (0..len).map(|_| Signal::default()).collect(),
+        }
+    }
+}
+
+impl<C: Cell> Tape for Fixed<C> {
+    type Cell = C;
+
+    fn contains(&self, idx: usize) -> bool {
+        idx < self.cells.len()
+    }
+
+    unsafe fn cell(&self, idx: usize) -> &C {
+        debug_assert!(self.contains(idx));
+        unsafe { self.cells.get_unchecked(idx) }
+    }
+
+    fn lock(&self, idx: usize) -> &AtomicBool {
+        &self.locks[idx]
+    }
+
+    fn signal(&self, idx: usize) -> &Signal {
+        &self.signals[idx]
+    }
+}
+
+/// A tape covering `-GROW_LIMIT..GROW_LIMIT` that allocates its cells, locks and signals chunk
+/// by chunk on first touch (`--tape-grow`). Chunks never move, so one thread growing the tape
+/// leaves the cells other threads are using in place.
+pub struct Growable<C> {
+    cells: Chunks<C>,
+    locks: Chunks<AtomicBool>,
+    signals: Chunks<Signal>,
+}
+
+impl<C: Cell> Growable<C> {
+    pub fn new() -> Self {
+        Growable {
+            cells: Chunks::new(),
+            locks: Chunks::new(),
+            signals: Chunks::new(),
+        }
+    }
+}
+
+impl<C: Cell> Tape for Growable<C> {
+    type Cell = C;
+
+    fn contains(&self, idx: usize) -> bool {
+        idx.wrapping_add(GROW_LIMIT as usize) < 2 * GROW_LIMIT as usize
+    }
+
+    unsafe fn cell(&self, idx: usize) -> &C {
+        self.cells.at(idx)
+    }
+
+    fn lock(&self, idx: usize) -> &AtomicBool {
+        self.locks.at(idx)
+    }
+
+    fn signal(&self, idx: usize) -> &Signal {
+        self.signals.at(idx)
+    }
+}
+
+/// Directory of lazily created chunks, with cell 0 at the start of the middle chunk
+struct Chunks<T> {
+    dir: Box<[OnceLock<Box<[T]>>]>,
+}
+
+impl<T: Default> Chunks<T> {
+    fn new() -> Self {
+        Chunks {
+            dir: (0..GROW_CHUNKS).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    fn at(&self, idx: usize) -> &T {
+        let chunk_len = 1 << GROW_CHUNK_BITS;
+        // Arithmetic shift, so cells left of 0 land in the lower half of the directory
+        let slot = ((idx as isize >> GROW_CHUNK_BITS) + GROW_CHUNKS as isize / 2) as usize;
+        let chunk = self.dir[slot].get_or_init(|| (0..chunk_len).map(|_| T::default()).collect());
+        &chunk[idx & (chunk_len - 1)]
+    }
+}
@@ -0,0 +1,155 @@
+// Just enough of an x86-64 encoder for the JIT. Register roles inside generated code:
+//   r12 = address of the current cell, r13 = tape base, r14 = the thread's `Thread` context.
+// All three are callee-saved, so runtime calls leave them intact.
+
+/// Machine code of one function being assembled
+pub struct Assembler {
+    pub code: Vec<u8>,
+}
+
+/// A rel32 jump whose target is not known yet
+pub struct Fixup(usize);
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self { code: Vec::new() }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, v: i32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    /// Save callee-saved registers as the asm backend's prologue does, then load r14 = rdi
+    /// (context), r12 = rsi (cell), r13 = rdx (tape base)
+    pub fn prologue(&mut self) {
+        self.bytes(&[0x53]); // push rbx
+        self.bytes(&[0x41, 0x54]); // push r12
+        self.bytes(&[0x41, 0x55]); // push r13
+        self.bytes(&[0x41, 0x56]); // push r14
+        self.bytes(&[0x41, 0x57]); // push r15
+        self.bytes(&[0x49, 0x89, 0xFE]); // mov r14, rdi
+        self.bytes(&[0x49, 0x89, 0xF4]); // mov r12, rsi
+        self.bytes(&[0x49, 0x89, 0xD5]); // mov r13, rdx
+    }
+
+    pub fn epilogue(&mut self) {
+        self.bytes(&[0x41, 0x5F]); // pop r15
+        self.bytes(&[0x41, 0x5E]); // pop r14
+        self.bytes(&[0x41, 0x5D]); // pop r13
+        self.bytes(&[0x41, 0x5C]); // pop r12
+        self.bytes(&[0x5B]); // pop rbx
+        self.bytes(&[0xC3]); // ret
+    }
+
+    /// add r12, delta
+    pub fn move_ptr(&mut self, delta: i32) {
+        self.bytes(&[0x49, 0x81, 0xC4]);
+        self.imm32(delta);
+    }
+
+    /// add byte [r12 + offset], amount
+    pub fn add_cell(&mut self, offset: i32, amount: i8) {
+        self.bytes(&[0x41, 0x80, 0x84, 0x24]);
+        self.imm32(offset);
+        self.bytes(&[amount as u8]);
+    }
+
+    /// mov byte [r12 + offset], 0
+    pub fn clear_cell(&mut self, offset: i32) {
+        self.bytes(&[0x41, 0xC6, 0x84, 0x24]);
+        self.imm32(offset);
+        self.bytes(&[0]);
+    }
+
+    /// movzx eax, byte [r12]
+    pub fn load_cell_eax(&mut self) {
+        self.bytes(&[0x41, 0x0F, 0xB6, 0x04, 0x24]);
+    }
+
+    /// imul ecx, eax, factor; add byte [r12 + offset], cl
+    pub fn add_product(&mut self, offset: i32, factor: i8) {
+        self.bytes(&[0x6B, 0xC8, factor as u8]);
+        self.bytes(&[0x41, 0x00, 0x8C, 0x24]);
+        self.imm32(offset);
+    }
+
+    /// cmp byte [r12], 0
+    pub fn test_cell(&mut self) {
+        self.bytes(&[0x41, 0x80, 0x3C, 0x24, 0x00]);
+    }
+
+    /// jz to a target patched later
+    pub fn jz_forward(&mut self) -> Fixup {
+        self.bytes(&[0x0F, 0x84]);
+        self.imm32(0);
+        Fixup(self.code.len())
+    }
+
+    /// Point a forward jump at the current position
+    pub fn bind(&mut self, fixup: Fixup) {
+        let rel = (self.code.len() - fixup.0) as i32;
+        self.code[fixup.0 - 4..fixup.0].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    /// jmp to an earlier position
+    pub fn jmp_back(&mut self, target: usize) {
+        self.bytes(&[0xE9]);
+        let rel = target as i64 - (self.code.len() + 4) as i64;
+        self.imm32(rel as i32);
+    }
+
+    /// rdi = context
+    pub fn arg_context(&mut self) {
+        self.bytes(&[0x4C, 0x89, 0xF7]); // mov rdi, r14
+    }
+
+    /// rsi = address of the current cell
+    pub fn arg_cell_ptr(&mut self) {
+        self.bytes(&[0x4C, 0x89, 0xE6]); // mov rsi, r12
+    }
+
+    /// rsi = tape index of the current cell
+    pub fn arg_cell_index(&mut self) {
+        self.arg_cell_ptr();
+        self.bytes(&[0x4C, 0x29, 0xEE]); // sub rsi, r13
+    }
+
+    /// esi = value of the current cell
+    pub fn arg_cell_value(&mut self) {
+        self.bytes(&[0x41, 0x0F, 0xB6, 0x34, 0x24]); // movzx esi, byte [r12]
+    }
+
+    /// esi = imm
+    pub fn arg2_imm(&mut self, v: u32) {
+        self.bytes(&[0xBE]);
+        self.imm32(v as i32);
+    }
+
+    /// edi = imm
+    pub fn arg1_imm(&mut self, v: u32) {
+        self.bytes(&[0xBF]);
+        self.imm32(v as i32);
+    }
+
+    /// edx = imm
+    pub fn arg3_imm(&mut self, v: u32) {
+        self.bytes(&[0xBA]);
+        self.imm32(v as i32);
+    }
+
+    /// rdx = address of the current cell
+    pub fn arg3_cell_ptr(&mut self) {
+        self.bytes(&[0x4C, 0x89, 0xE2]); // mov rdx, r12
+    }
+
+    /// Call an absolute address through rax
+    pub fn call(&mut self, f: *const ()) {
+        self.bytes(&[0x48, 0xB8]); // mov rax, imm64
+        self.bytes(&(f as u64).to_le_bytes());
+        self.bytes(&[0xFF, 0xD0]); // call rax
+    }
+}
@@ -0,0 +1,144 @@
+use std::io::{Read, Result, Write};
+use std::sync::Mutex;
+
+use crate::codegen::Eof;
+use crate::interpreter::{RBound, WBound};
+use crate::parser::{Node, NodeKind};
+
+mod asm;
+mod runtime;
+
+use asm::Assembler;
+
+// The program is compiled to one native function per thread body: the top level and every
+// parallel branch. They share the calling convention `fn(ctx, cell, tape_base)` and reach the
+// runtime only for I/O, sync operations, sleeping and spawning, mirroring the LLVM backend.
+
+/// Compiled functions before they are copied into executable memory
+struct Program {
+    funcs: Vec<Vec<u8>>,
+    parallels: Vec<Vec<usize>>, // Function index of every branch, per parallel block
+}
+
+fn compile(nodes: &[Node]) -> Program {
+    let mut prog = Program {
+        funcs: Vec::new(),
+        parallels: Vec::new(),
+    };
+    compile_func(&mut prog, nodes);
+    prog
+}
+
+/// Compile a thread body into a new function and return its index; the top level is index 0
+fn compile_func(prog: &mut Program, nodes: &[Node]) -> usize {
+    let id = prog.funcs.len();
+    prog.funcs.push(Vec::new());
+    let mut a = Assembler::new();
+    a.prologue();
+    compile_nodes(prog, &mut a, nodes);
+    a.epilogue();
+    prog.funcs[id] = a.code;
+    id
+}
+
+fn compile_nodes(prog: &mut Program, a: &mut Assembler, nodes: &[Node]) {
+    for n in nodes {
+        match &n.kind {
+            NodeKind::IncPtr => a.move_ptr(1),
+            NodeKind::DecPtr => a.move_ptr(-1),
+            NodeKind::Move(d) => a.move_ptr(*d as i32),
+            NodeKind::IncCell => a.add_cell(0, 1),
+            NodeKind::DecCell => a.add_cell(0, -1),
+            NodeKind::Add(d) => a.add_cell(0, *d as i8), // Cells wrap at 8 bits
+            NodeKind::Clear => a.clear_cell(0),
+            NodeKind::MulAdd(targets) => {
+                // Skipped on a zero cell, see `NodeKind::MulAdd`
+                a.test_cell();
+                let skip = a.jz_forward();
+                a.load_cell_eax();
+                for (offset, factor) in targets {
+                    a.add_product(*offset as i32, *factor as i8);
+                }
+                a.clear_cell(0);
+                a.bind(skip);
+            }
+            NodeKind::Scan(step) => {
+                let top = a.code.len();
+                a.test_cell();
+                let exit = a.jz_forward();
+                a.move_ptr(*step as i32);
+                a.jmp_back(top);
+                a.bind(exit);
+            }
+            NodeKind::Loop(body) => {
+                let top = a.code.len();
+                a.test_cell();
+                let exit = a.jz_forward();
+                compile_nodes(prog, a, body);
+                a.jmp_back(top);
+                a.bind(exit);
+            }
+            NodeKind::Output => {
+                a.arg_context();
+                a.arg_cell_value();
+                a.call(runtime::jit_output as *const ());
+            }
+            NodeKind::Input => {
+                a.arg_context();
+                a.arg_cell_ptr();
+                a.call(runtime::jit_input as *const ());
+            }
+            NodeKind::LockAcquire => index_call(a, runtime::jit_lock_acquire as *const ()),
+            NodeKind::LockRelease => {
+                a.arg_context();
+                a.arg2_imm(n.span.line as u32);
+                a.arg3_imm(n.span.col as u32);
+                a.call(runtime::jit_lock_release as *const ());
+            }
+            NodeKind::Wait => index_call(a, runtime::jit_wait as *const ()),
+            NodeKind::Notify => index_call(a, runtime::jit_notify as *const ()),
+            NodeKind::Sleep(t) => {
+                a.arg1_imm(*t as u32);
+                a.call(runtime::jit_sleep as *const ());
+            }
+            NodeKind::Parallel(branches) => {
+                let funcs = branches.iter().map(|b| compile_func(prog, b)).collect();
+                let id = prog.parallels.len();
+                prog.parallels.push(funcs);
+                a.arg_context();
+                a.arg2_imm(id as u32);
+                a.arg3_cell_ptr();
+                a.call(runtime::jit_parallel as *const ());
+            }
+        }
+    }
+}
+
+/// Call `helper(ctx, index of the current cell)`
+fn index_call(a: &mut Assembler, helper: *const ()) {
+    a.arg_context();
+    a.arg_cell_index();
+    a.call(helper);
+}
+
+pub struct Jit {
+    input: Mutex<Box<dyn Read + Send>>,
+    output: Mutex<Box<dyn Write + Send>>,
+    tape_len: usize,
+    eof: Eof,
+}
+
+impl Jit {
+    pub fn new(input: impl RBound, output: impl WBound, tape_len: usize, eof: Eof) -> Self {
+        Jit {
+            input: Mutex::new(Box::new(input)),
+            output: Mutex::new(Box::new(output)),
+            tape_len,
+            eof,
+        }
+    }
+
+    pub fn run(&self, nodes: &[Node]) -> Result<()> {
+        runtime::execute(self, &compile(nodes))
+    }
+}
@@ -0,0 +1,213 @@
+use std::cell::UnsafeCell;
+use std::io::{Error, Result};
+use std::thread;
+use std::time::Duration;
+use std::{process, ptr};
+
+use super::{Jit, Program};
+use crate::codegen::Eof;
+
+/// Entry point of a compiled thread body
+type Entry = unsafe extern "C" fn(*mut Thread, *mut u8, *mut u8);
+
+/// Anonymous private mapping, unmapped on drop
+struct Mapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(len: usize, prot: i32) -> Result<Self> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        Ok(Mapping {
+            addr: addr.cast(),
+            len,
+        })
+    }
+
+    fn protect(&self, offset: usize, len: usize, prot: i32) -> Result<()> {
+        if unsafe { libc::mprotect(self.addr.add(offset).cast(), len, prot) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr.cast(), self.len) };
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn round_up(n: usize, to: usize) -> usize {
+    n.div_ceil(to) * to
+}
+
+/// Per-cell pthread objects, shared by all threads
+struct Slab<T>(Box<[UnsafeCell<T>]>);
+
+unsafe impl<T> Sync for Slab<T> {}
+
+impl<T: Copy> Slab<T> {
+    fn new(len: usize, init: T) -> Self {
+        Slab((0..len).map(|_| UnsafeCell::new(init)).collect())
+    }
+
+    fn get(&self, idx: usize) -> *mut T {
+        self.0[idx].get()
+    }
+}
+
+/// State shared by every thread of a running program
+struct Shared<'a> {
+    jit: &'a Jit,
+    tape: *mut u8,
+    entries: Vec<usize>, // Address of every compiled function
+    parallels: &'a [Vec<usize>],
+    locks: Slab<libc::pthread_mutex_t>,
+    conds: Slab<libc::pthread_cond_t>,
+    cond_mtx: Slab<libc::pthread_mutex_t>,
+}
+
+unsafe impl Sync for Shared<'_> {}
+
+/// Per-thread context handed to compiled code in rdi
+pub struct Thread<'a> {
+    shared: &'a Shared<'a>,
+    lock_stack: Vec<usize>,
+}
+
+impl<'a> Shared<'a> {
+    /// Run compiled function `func` on a new thread context, starting at `cell`
+    fn enter(&'a self, func: usize, cell: *mut u8) {
+        let mut t = Thread {
+            shared: self,
+            lock_stack: Vec::new(),
+        };
+        let entry: Entry = unsafe { std::mem::transmute(self.entries[func]) };
+        unsafe { entry(&mut t, cell, self.tape) };
+    }
+}
+
+/// Copy the program into executable memory and run it on a fresh tape
+pub fn execute(jit: &Jit, prog: &Program) -> Result<()> {
+    let page = page_size();
+
+    // Guard pages on both sides of the tape turn most runaway pointers into a fault instead
+    // of silent corruption of the engine's own memory
+    let tape_len = round_up(jit.tape_len, page);
+    let tape = Mapping::new(tape_len + 2 * page, libc::PROT_NONE)?;
+    tape.protect(page, tape_len, libc::PROT_READ | libc::PROT_WRITE)?;
+
+    let code_len = round_up(prog.funcs.iter().map(Vec::len).sum::<usize>().max(1), page);
+    let code = Mapping::new(code_len, libc::PROT_READ | libc::PROT_WRITE)?;
+    let mut entries = Vec::with_capacity(prog.funcs.len());
+    let mut at = 0;
+    for func in &prog.funcs {
+        unsafe { ptr::copy_nonoverlapping(func.as_ptr(), code.addr.add(at), func.len()) };
+        entries.push(code.addr as usize + at);
+        at += func.len();
+    }
+    code.protect(0, code_len, libc::PROT_READ | libc::PROT_EXEC)?;
+
+    let shared = Shared {
+        jit,
+        tape: unsafe { tape.addr.add(page) },
+        entries,
+        parallels: &prog.parallels,
+        locks: Slab::new(jit.tape_len, libc::PTHREAD_MUTEX_INITIALIZER),
+        conds: Slab::new(jit.tape_len, libc::PTHREAD_COND_INITIALIZER),
+        cond_mtx: Slab::new(jit.tape_len, libc::PTHREAD_MUTEX_INITIALIZER),
+    };
+    shared.enter(0, shared.tape);
+    Ok(())
+}
+
+// Runtime helpers called from compiled code. `t` is always the context the calling thread was
+// entered with.
+
+pub unsafe extern "C" fn jit_output(t: *mut Thread, value: u32) {
+    let t = unsafe { &*t };
+    let mut out = t.shared.jit.output.lock().unwrap();
+    let _ = out.write_all(&[value as u8]).and_then(|()| out.flush());
+}
+
+pub unsafe extern "C" fn jit_input(t: *mut Thread, cell: *mut u8) {
+    let t = unsafe { &*t };
+    let mut buf = [0];
+    let mut inp = t.shared.jit.input.lock().unwrap();
+    let value = match inp.read_exact(&mut buf) {
+        Ok(()) => buf[0],
+        Err(_) => match t.shared.jit.eof {
+            Eof::Unchanged => return,
+            Eof::Zero => 0,
+            Eof::MinusOne => u8::MAX,
+        },
+    };
+    unsafe { *cell = value };
+}
+
+pub unsafe extern "C" fn jit_lock_acquire(t: *mut Thread, idx: usize) {
+    let t = unsafe { &mut *t };
+    unsafe { libc::pthread_mutex_lock(t.shared.locks.get(idx)) };
+    t.lock_stack.push(idx);
+}
+
+/// A panic cannot unwind out of compiled code, so a release with no held lock reports and exits
+pub unsafe extern "C" fn jit_lock_release(t: *mut Thread, line: u32, col: u32) {
+    let t = unsafe { &mut *t };
+    let Some(idx) = t.lock_stack.pop() else {
+        eprintln!("error: {line}:{col}: LockRelease without matching LockAcquire");
+        process::exit(1);
+    };
+    unsafe { libc::pthread_mutex_unlock(t.shared.locks.get(idx)) };
+}
+
+pub unsafe extern "C" fn jit_wait(t: *mut Thread, idx: usize) {
+    let s = unsafe { (*t).shared };
+    unsafe {
+        libc::pthread_mutex_lock(s.cond_mtx.get(idx));
+        libc::pthread_cond_wait(s.conds.get(idx), s.cond_mtx.get(idx));
+        libc::pthread_mutex_unlock(s.cond_mtx.get(idx));
+    }
+}
+
+pub unsafe extern "C" fn jit_notify(t: *mut Thread, idx: usize) {
+    let s = unsafe { (*t).shared };
+    unsafe {
+        libc::pthread_mutex_lock(s.cond_mtx.get(idx));
+        libc::pthread_cond_broadcast(s.conds.get(idx));
+        libc::pthread_mutex_unlock(s.cond_mtx.get(idx));
+    }
+}
+
+pub extern "C" fn jit_sleep(ticks: u32) {
+    thread::sleep(Duration::from_millis(100 * u64::from(ticks)));
+}
+
+/// Run every branch of parallel block `id` on its own thread, starting at `cell`, and join them
+pub unsafe extern "C" fn jit_parallel(t: *mut Thread, id: u32, cell: *mut u8) {
+    let s = unsafe { (*t).shared };
+    let cell = cell as usize; // Raw pointers are not Send
+    thread::scope(|scope| {
+        for &func in &s.parallels[id as usize] {
+            scope.spawn(move || s.enter(func, cell as *mut u8));
+        }
+    });
+}
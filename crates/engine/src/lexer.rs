@@ -1,5 +1,24 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::diagnostics::Diagnostic;
+
+/// 1-based line/column position of a character in the source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub enum Token {
+pub enum TokenKind {
     IncPtr,
     DecPtr,
     IncCell,
@@ -18,36 +37,132 @@ pub enum Token {
     Notify,
 }
 
-pub fn lex(input: &str) -> Vec<Token> {
-    let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
-
-    while let Some(c) = chars.next() {
-        match c {
-            '>' => tokens.push(Token::IncPtr),
-            '<' => tokens.push(Token::DecPtr),
-            '+' => tokens.push(Token::IncCell),
-            '-' => tokens.push(Token::DecCell),
-            '.' => tokens.push(Token::Output),
-            ',' => tokens.push(Token::Input),
-            '[' => tokens.push(Token::LoopStart),
-            ']' => tokens.push(Token::LoopEnd),
-            '{' => tokens.push(Token::ParStart),
-            '|' => tokens.push(Token::ParSep),
-            '}' => tokens.push(Token::ParEnd),
-            '(' => tokens.push(Token::LockStart),
-            ')' => tokens.push(Token::LockEnd),
-            '~' => tokens.push(Token::Sleep),
-            '^' => tokens.push(Token::Wait),
-            'v' => tokens.push(Token::Notify),
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// A `;` line comment or `/* ... */` block comment kept for the formatter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
+    /// Whether code precedes the comment on the same line
+    pub trailing: bool,
+}
+
+/// Which characters are instructions; everything else is a comment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// The eight classic Brainfuck commands only
+    Brainfuck,
+    /// Brainfuck plus parallel blocks, locks, sleep, wait and notify
+    Brainfork,
+}
+
+impl Dialect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "brainfuck" | "bf" => Some(Dialect::Brainfuck),
+            "brainfork" => Some(Dialect::Brainfork),
+            _ => None,
+        }
+    }
+}
+
+/// Character iterator that tracks the span of the next character
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    span: Span,
+}
+
+impl Cursor<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.span.line += 1;
+            self.span.col = 1;
+        } else {
+            self.span.col += 1;
+        }
+        Some(c)
+    }
+}
+
+/// Tokenize `input`, also returning the comments in source order. `;` starts a comment
+/// running to the end of the line and `/* ... */` is a block comment in both dialects.
+pub fn lex(input: &str, dialect: Dialect) -> Result<(Vec<Token>, Vec<Comment>), Diagnostic> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut comments = Vec::new();
+    let mut cur = Cursor {
+        chars: input.chars().peekable(),
+        span: Span { line: 1, col: 1 },
+    };
+
+    loop {
+        let span = cur.span;
+        let Some(c) = cur.bump() else { break };
+        let kind = match c {
+            '>' => TokenKind::IncPtr,
+            '<' => TokenKind::DecPtr,
+            '+' => TokenKind::IncCell,
+            '-' => TokenKind::DecCell,
+            '.' => TokenKind::Output,
+            ',' => TokenKind::Input,
+            '[' => TokenKind::LoopStart,
+            ']' => TokenKind::LoopEnd,
             ';' => {
-                while chars.peek().is_some() && *chars.peek().unwrap() != '\n' {
-                    chars.next();
+                let mut text = String::from(c);
+                while let Some(c) = cur.peek().filter(|&c| c != '\n') {
+                    text.push(c);
+                    cur.bump();
                 }
+                comments.push(comment(text, span, &tokens));
+                continue;
             }
-            _ => {}
-        }
+            '/' if cur.peek() == Some('*') => {
+                let mut text = String::from("/*");
+                cur.bump();
+                loop {
+                    match cur.bump() {
+                        Some('*') if cur.peek() == Some('/') => {
+                            cur.bump();
+                            text.push_str("*/");
+                            break;
+                        }
+                        Some(c) => text.push(c),
+                        None => return Err(Diagnostic::error(span, "unterminated block comment")),
+                    }
+                }
+                comments.push(comment(text, span, &tokens));
+                continue;
+            }
+            _ if dialect == Dialect::Brainfuck => continue,
+            '{' => TokenKind::ParStart,
+            '|' => TokenKind::ParSep,
+            '}' => TokenKind::ParEnd,
+            '(' => TokenKind::LockStart,
+            ')' => TokenKind::LockEnd,
+            '~' => TokenKind::Sleep,
+            '^' => TokenKind::Wait,
+            'v' => TokenKind::Notify,
+            _ => continue,
+        };
+        tokens.push(Token { kind, span });
     }
 
-    tokens
+    Ok((tokens, comments))
+}
+
+fn comment(text: String, span: Span, tokens: &[Token]) -> Comment {
+    Comment {
+        text: text.trim_end().to_string(),
+        span,
+        trailing: tokens.last().is_some_and(|t| t.span.line == span.line),
+    }
 }
@@ -0,0 +1,148 @@
+use crate::diagnostics::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::{Node, NodeKind};
+
+/// Check that every `(` is matched by a `)` on every path through the program.
+///
+/// Each thread owns its lock stack, so parallel branches start out empty and are checked
+/// independently. Releasing with an empty stack and loops whose body changes the stack depth
+/// are errors; locks still held when a thread or the program ends are warnings.
+pub fn check(nodes: &[Node]) -> Vec<Diagnostic> {
+    let mut diags = Vec::new();
+    check_thread(nodes, &mut diags);
+    diags.sort_by_key(|d| d.span);
+    diags
+}
+
+fn check_thread(nodes: &[Node], diags: &mut Vec<Diagnostic>) {
+    let mut held = Vec::new();
+    walk(nodes, &mut held, diags);
+    for span in held {
+        diags.push(Diagnostic::warning(
+            span,
+            "lock acquired here is still held when the thread ends",
+        ));
+    }
+}
+
+/// Simulate the lock stack of one thread; `held` holds the spans of the acquiring `(`s
+fn walk(nodes: &[Node], held: &mut Vec<Span>, diags: &mut Vec<Diagnostic>) {
+    for node in nodes {
+        match &node.kind {
+            NodeKind::LockAcquire => held.push(node.span),
+            NodeKind::LockRelease if held.pop().is_none() => {
+                diags.push(Diagnostic::error(
+                    node.span,
+                    "')' releases a lock but none is held",
+                ));
+            }
+            NodeKind::LockRelease => {}
+            NodeKind::Loop(body) => {
+                let saved = held.clone();
+                let before = held.len();
+                walk(body, held, diags);
+                let after = held.len();
+                if after != before {
+                    let msg = if after > before {
+                        format!(
+                            "loop body acquires {} more lock(s) than it releases",
+                            after - before
+                        )
+                    } else {
+                        format!(
+                            "loop body releases {} more lock(s) than it acquires",
+                            before - after
+                        )
+                    };
+                    diags.push(Diagnostic::error(node.span, msg));
+                }
+                // The body may run zero times; continue as if it had not run at all
+                *held = saved;
+            }
+            NodeKind::Parallel(branches) => {
+                for branch in branches {
+                    check_thread(branch, diags);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::diagnostics::Severity::{self, *};
+    use crate::opt::tests::tree;
+
+    /// Severity, `(line, col)` and message of every diagnostic for `src`
+    fn diags(src: &str) -> Vec<(Severity, (usize, usize), String)> {
+        check(&tree(src))
+            .into_iter()
+            .map(|d| (d.severity, (d.span.line, d.span.col), d.message))
+            .collect()
+    }
+
+    fn at(severity: Severity, col: usize, msg: &str) -> (Severity, (usize, usize), String) {
+        (severity, (1, col), msg.to_string())
+    }
+
+    const NONE_HELD: &str = "')' releases a lock but none is held";
+    const STILL_HELD: &str = "lock acquired here is still held when the thread ends";
+
+    #[test]
+    fn accepts_balanced_locks() {
+        assert!(diags("(+)((>)<)[(-)]{(.)|>(+)}").is_empty());
+    }
+
+    #[test]
+    fn reports_unbalanced_locks() {
+        assert_eq!(diags("+)"), [at(Error, 2, NONE_HELD)]);
+        assert_eq!(diags("(()"), [at(Warning, 1, STILL_HELD)]);
+    }
+
+    #[test]
+    fn reports_loops_that_change_the_lock_depth() {
+        assert_eq!(
+            diags("[(]"),
+            [at(
+                Error,
+                1,
+                "loop body acquires 1 more lock(s) than it releases"
+            )]
+        );
+        // The body may run zero times, so both locks are still held after it
+        assert_eq!(
+            diags("(([))])))"),
+            [
+                at(
+                    Error,
+                    3,
+                    "loop body releases 2 more lock(s) than it acquires"
+                ),
+                at(Error, 9, NONE_HELD),
+            ]
+        );
+        assert_eq!(diags("[)]"), [at(Error, 2, NONE_HELD)]);
+    }
+
+    #[test]
+    fn checks_parallel_branches_on_their_own() {
+        // A branch cannot release a lock its parent holds, nor leave one held for it
+        assert_eq!(
+            diags("({)|(})"),
+            [at(Error, 3, NONE_HELD), at(Warning, 5, STILL_HELD)]
+        );
+        assert_eq!(
+            diags("{[(]|[)]}"),
+            [
+                at(
+                    Error,
+                    2,
+                    "loop body acquires 1 more lock(s) than it releases"
+                ),
+                at(Error, 7, NONE_HELD),
+            ]
+        );
+    }
+}
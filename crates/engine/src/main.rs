@@ -1,17 +1,210 @@
 #![feature(trait_alias)]
 
+use std::path::{Path, PathBuf};
 use std::{env, fs, io, process};
 
+use diagnostics::Diagnostic;
+use lexer::Dialect;
+
+mod asmgen;
+mod build;
+mod cgen;
 mod codegen;
+mod diagnostics;
+mod formatter;
 mod interpreter;
+#[cfg(target_arch = "x86_64")]
+mod jit;
 mod lexer;
+mod lockcheck;
+mod opt;
 mod parser;
 
 fn usage(prog: &str) -> ! {
-    eprintln!("Usage: {prog} (compile|c|interpret|i) <source.bf>");
+    eprintln!(
+        "Usage: {prog} (compile|c|build|b|interpret|i|fmt) <source.bf> [-o <executable>] [--dialect=brainfork|brainfuck] [--sanitize [--runtime <libruntime.a>]] [--debug-info] [--buffer=none|line|full] [--tape-size <n>]
+       [--cell-bits=8|16|32|64] [--bounds-check|--tape-wrap|--tape-grow] [--eof=zero|unchanged|minus-one] [--emit=ir|c|asm] [--typed-pointers] [--jit] [-O0|-O1|-O2|-O3] [--pass=<name>,...] [--no-pass=<name>,...] [--print-after-each]
+Passes: {}
+--tape-grow extends the tape on demand up to {} cells (2^{}) either side of cell 0; moving further stops with a tape-limit error",
+        opt::PASSES.iter().map(|p| p.name).collect::<Vec<_>>().join(", "),
+        codegen::GROW_LIMIT,
+        codegen::GROW_LIMIT.trailing_zeros()
+    );
     process::exit(1);
 }
 
+/// File name and absolute directory of `path` for DWARF debug info
+fn debug_source(path: &str) -> codegen::DebugSource {
+    let abs = fs::canonicalize(path).unwrap_or_else(|_| Path::new(path).to_path_buf());
+    codegen::DebugSource {
+        filename: abs
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        directory: abs
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+/// Number of tape cells from `--tape-size <n>` (default 30000)
+fn tape_len(args: &[String]) -> i64 {
+    match args.iter().position(|a| a == "--tape-size") {
+        Some(i) => {
+            let n = args.get(i + 1).unwrap_or_else(|| usage(&args[0]));
+            n.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+                eprintln!("Invalid tape size: {n}");
+                process::exit(1);
+            })
+        }
+        None => codegen::DEFAULT_TAPE_LEN,
+    }
+}
+
+/// Cell width from `--cell-bits=<n>` (default 8)
+fn cell_bits(args: &[String]) -> u32 {
+    match args.iter().find_map(|a| a.strip_prefix("--cell-bits=")) {
+        Some(n) => n
+            .parse()
+            .ok()
+            .filter(|n| codegen::CELL_BITS.contains(n))
+            .unwrap_or_else(|| {
+                eprintln!("Invalid cell width: {n} (expected 8, 16, 32 or 64)");
+                process::exit(1);
+            }),
+        None => 8,
+    }
+}
+
+/// Bounds mode from at most one of `--bounds-check`, `--tape-wrap` and `--tape-grow`
+fn tape_bounds(args: &[String]) -> codegen::TapeBounds {
+    let modes = [
+        ("--bounds-check", codegen::TapeBounds::Checked),
+        ("--tape-wrap", codegen::TapeBounds::Wrap),
+        ("--tape-grow", codegen::TapeBounds::Grow),
+    ];
+    let chosen: Vec<_> = modes
+        .iter()
+        .filter(|(flag, _)| args.iter().any(|a| a == flag))
+        .collect();
+    match chosen[..] {
+        [] => codegen::TapeBounds::Unchecked,
+        [(_, bounds)] => *bounds,
+        _ => {
+            let flags: Vec<_> = chosen.iter().map(|(flag, _)| *flag).collect();
+            eprintln!("{} cannot be combined", flags.join(" and "));
+            process::exit(1);
+        }
+    }
+}
+
+/// Optimization level from the last `-O<n>` flag (default -O2)
+fn opt_level(args: &[String]) -> u8 {
+    let mut level = 2;
+    for arg in args {
+        if let Some(n) = arg.strip_prefix("-O") {
+            level = n
+                .parse()
+                .ok()
+                .filter(|&n| n <= opt::MAX_LEVEL)
+                .unwrap_or_else(|| {
+                    eprintln!("Invalid optimization level: {arg}");
+                    process::exit(1);
+                });
+        }
+    }
+    level
+}
+
+/// Build the pass pipeline from the optimization level and per-pass overrides
+fn pass_manager(args: &[String]) -> opt::PassManager {
+    let mut pm = opt::PassManager::new(opt_level(args));
+    for arg in args {
+        let (names, on) = if let Some(names) = arg.strip_prefix("--pass=") {
+            (names, true)
+        } else if let Some(names) = arg.strip_prefix("--no-pass=") {
+            (names, false)
+        } else {
+            continue;
+        };
+        for name in names.split(',') {
+            pm.set_enabled(name, on).unwrap_or_else(|err| {
+                eprintln!("{err}");
+                process::exit(1);
+            });
+        }
+    }
+    pm.print_after_each = args.iter().any(|a| a == "--print-after-each");
+    pm
+}
+
+fn codegen_options(args: &[String], path: &str) -> codegen::Options {
+    codegen::Options {
+        sanitize: args.iter().any(|a| a == "--sanitize" || a == "-s"),
+        debug_info: args
+            .iter()
+            .any(|a| a == "--debug-info" || a == "-g")
+            .then(|| debug_source(path)),
+        output: match args.iter().find_map(|a| a.strip_prefix("--buffer=")) {
+            Some(name) => codegen::OutputBuffering::from_name(name).unwrap_or_else(|| {
+                eprintln!("Unknown output buffering mode: {name}");
+                process::exit(1);
+            }),
+            None => codegen::OutputBuffering::None,
+        },
+        typed_pointers: args.iter().any(|a| a == "--typed-pointers"),
+        tape_len: tape_len(args),
+        cell_bits: cell_bits(args),
+        bounds: tape_bounds(args),
+        eof: match args.iter().find_map(|a| a.strip_prefix("--eof=")) {
+            Some(name) => codegen::Eof::from_name(name).unwrap_or_else(|| {
+                eprintln!("Unknown end-of-input behavior: {name}");
+                process::exit(1);
+            }),
+            None => codegen::Eof::Zero,
+        },
+    }
+}
+
+/// Language `compile` and `build` generate code in
+#[derive(PartialEq, Eq)]
+enum Emit {
+    LlvmIr,
+    C,
+    Asm,
+}
+
+fn emit_target(args: &[String]) -> Emit {
+    match args.iter().find_map(|a| a.strip_prefix("--emit=")) {
+        None | Some("ir") => Emit::LlvmIr,
+        Some("c") => Emit::C,
+        Some("asm") => Emit::Asm,
+        Some(name) => {
+            eprintln!("Unknown output language: {name}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Race-detecting runtime library from `--runtime <path>`, if given
+fn runtime_path(args: &[String]) -> Option<PathBuf> {
+    let i = args.iter().position(|a| a == "--runtime")?;
+    Some(PathBuf::from(
+        args.get(i + 1).unwrap_or_else(|| usage(&args[0])),
+    ))
+}
+
+/// Executable path from `-o <path>`, defaulting to the source file name without its extension
+fn output_path(args: &[String], path: &str) -> PathBuf {
+    match args.iter().position(|a| a == "-o") {
+        Some(i) => PathBuf::from(args.get(i + 1).unwrap_or_else(|| usage(&args[0]))),
+        None => Path::new(path).with_extension(""),
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
@@ -24,17 +217,146 @@ fn main() {
         process::exit(1);
     });
 
-    let tokens = lexer::lex(&contents);
-    let nodes = parser::parse(&tokens);
+    let dialect = match args.iter().find_map(|a| a.strip_prefix("--dialect=")) {
+        Some(name) => Dialect::from_name(name).unwrap_or_else(|| {
+            eprintln!("Unknown dialect: {name}");
+            process::exit(1);
+        }),
+        None => Dialect::Brainfork,
+    };
+
+    let (tokens, comments) = lexer::lex(&contents, dialect).unwrap_or_else(|diag| {
+        eprintln!("{}", diag.render(path, &contents));
+        process::exit(1);
+    });
+    let nodes = parser::parse(&tokens).unwrap_or_else(|errors| {
+        for err in &errors {
+            eprintln!("{}\n", Diagnostic::from(err).render(path, &contents));
+        }
+        eprintln!("error: aborting due to {} parse error(s)", errors.len());
+        process::exit(1);
+    });
+
+    if cmd == "fmt" {
+        print!("{}", formatter::format(&nodes, &tokens, &comments));
+        return;
+    }
+
+    let diags = lockcheck::check(&nodes);
+    for diag in &diags {
+        eprintln!("{}\n", diag.render(path, &contents));
+    }
+    let errors = diags.iter().filter(|d| d.is_error()).count();
+    if errors > 0 {
+        eprintln!("error: aborting due to {errors} lock error(s)");
+        process::exit(1);
+    }
+
+    let nodes = pass_manager(&args).run(nodes);
+    let opts = codegen_options(&args, path);
+    let emit = emit_target(&args);
 
     match cmd.as_str() {
+        "compile" | "c" | "build" | "b" if emit != Emit::LlvmIr && opts.sanitize => {
+            let backend = if emit == Emit::C { "C" } else { "assembly" };
+            eprintln!("The {backend} backend does not support --sanitize");
+            process::exit(1);
+        }
+        "compile" | "c" | "build" | "b"
+            if emit != Emit::LlvmIr && opts.bounds == codegen::TapeBounds::Grow =>
+        {
+            let backend = if emit == Emit::C { "C" } else { "assembly" };
+            eprintln!("The {backend} backend does not support --tape-grow");
+            process::exit(1);
+        }
+        "compile" | "c" | "build" | "b" if emit != Emit::LlvmIr && opts.debug_info.is_some() => {
+            let backend = if emit == Emit::C { "C" } else { "assembly" };
+            eprintln!("The {backend} backend does not support --debug-info");
+            process::exit(1);
+        }
+        "compile" | "c" if emit == Emit::C => {
+            print!("{}", cgen::generate_c(&nodes, &opts));
+        }
+        "compile" | "c" if emit == Emit::Asm => {
+            print!("{}", asmgen::generate_asm(&nodes, &opts));
+        }
         "compile" | "c" => {
-            let sanitize = args.iter().any(|a| a == "--sanitize" || a == "-s");
-            let ir = codegen::generate_ir(&nodes, sanitize);
+            let ir = codegen::generate_ir(&nodes, &opts);
             println!("{ir}");
         }
+        "build" | "b" if emit != Emit::LlvmIr => {
+            let build_opts = build::BuildOptions {
+                output: &output_path(&args, path),
+                sanitize: false,
+                runtime: None,
+                opt_level: opt_level(&args),
+            };
+            let result = if emit == Emit::C {
+                build::build_c(&cgen::generate_c(&nodes, &opts), &build_opts)
+            } else {
+                build::build_asm(&asmgen::generate_asm(&nodes, &opts), &build_opts)
+            };
+            if let Err(err) = result {
+                eprintln!("error: {err}");
+                process::exit(1);
+            }
+        }
+        "build" | "b" => {
+            let output = output_path(&args, path);
+            let runtime = runtime_path(&args);
+            let result = build::Toolchain::detect().and_then(|toolchain| {
+                let mut opts = opts;
+                opts.typed_pointers |= toolchain.needs_typed_pointers();
+                let ir = codegen::generate_ir(&nodes, &opts);
+                toolchain.build(
+                    &ir,
+                    &build::BuildOptions {
+                        output: &output,
+                        sanitize: opts.sanitize,
+                        runtime: runtime.as_deref(),
+                        opt_level: opt_level(&args),
+                    },
+                )
+            });
+            if let Err(err) = result {
+                eprintln!("error: {err}");
+                process::exit(1);
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        "interpret" | "i" if args.iter().any(|a| a == "--jit") => {
+            eprintln!("The JIT is only available on x86-64");
+            process::exit(1);
+        }
+        "interpret" | "i" if args.iter().any(|a| a == "--jit") && opts.cell_bits != 8 => {
+            eprintln!("The JIT only supports 8-bit cells");
+            process::exit(1);
+        }
+        "interpret" | "i"
+            if args.iter().any(|a| a == "--jit")
+                && opts.bounds != codegen::TapeBounds::Unchecked =>
+        {
+            eprintln!("The JIT does not support --bounds-check, --tape-wrap or --tape-grow");
+            process::exit(1);
+        }
+        #[cfg(target_arch = "x86_64")]
+        "interpret" | "i" if args.iter().any(|a| a == "--jit") => {
+            jit::Jit::new(io::stdin(), io::stdout(), opts.tape_len as usize, opts.eof)
+                .run(&nodes)
+                .unwrap_or_else(|err| {
+                    eprintln!("Failed to set up JIT memory: {err}");
+                    process::exit(1);
+                });
+        }
         "interpret" | "i" => {
-            let interpreter = interpreter::Interpreter::new(io::stdin(), io::stdout());
+            let interpreter = interpreter::Interpreter::new(
+                io::stdin(),
+                io::stdout(),
+                opts.tape_len as usize,
+                opts.cell_bits,
+                opts.bounds,
+                opts.eof,
+            );
             interpreter.run(&nodes).unwrap();
         }
         _ => usage(prog),
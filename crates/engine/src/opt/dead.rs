@@ -0,0 +1,61 @@
+use crate::parser::{Node, NodeKind};
+
+/// Remove loops that can never run because the current cell is known to be zero: at the very
+/// start of the program, and directly after a loop or loop idiom. This only holds while no other
+/// thread can write the cell, so bodies of parallel branches are left alone.
+pub fn dead_loops(nodes: Vec<Node>) -> Vec<Node> {
+    remove(nodes, true)
+}
+
+fn remove(nodes: Vec<Node>, mut zero: bool) -> Vec<Node> {
+    let mut out = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let kind = match node.kind {
+            NodeKind::Loop(_) if zero => continue,
+            NodeKind::Loop(body) => NodeKind::Loop(remove(body, false)),
+            kind => kind,
+        };
+        zero = matches!(
+            kind,
+            NodeKind::Loop(_) | NodeKind::Clear | NodeKind::Scan(_) | NodeKind::MulAdd(_)
+        );
+        out.push(Node {
+            kind,
+            span: node.span,
+        });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dead_loops;
+    use crate::opt::idiom::clear_loops;
+    use crate::opt::tests::{shape, tree};
+
+    fn pruned(src: &str) -> String {
+        shape(&dead_loops(clear_loops(tree(src))))
+    }
+
+    #[test]
+    fn removes_loops_on_a_zero_cell() {
+        assert_eq!(pruned("[->+<]+"), "IncCell");
+        assert_eq!(pruned("+[>][<]"), "IncCell [IncPtr]");
+        assert_eq!(pruned("+[-][+.]."), "IncCell Clear Output");
+    }
+
+    #[test]
+    fn keeps_loops_on_a_cell_that_may_be_nonzero() {
+        assert_eq!(pruned("+[>]>[<]"), "IncCell [IncPtr] IncPtr [DecPtr]");
+        assert_eq!(pruned(",[.]"), "Input [Output]");
+        // The first loop in a body runs on a nonzero cell
+        assert_eq!(pruned("+[[-]>]"), "IncCell [Clear IncPtr]");
+    }
+
+    #[test]
+    fn leaves_parallel_branches_alone() {
+        assert_eq!(pruned("{[.]|+}"), "{[Output] | IncCell}");
+        // Another thread may have written the cell by the time the block ends
+        assert_eq!(pruned("+[-]{+|}[.]"), "IncCell Clear {IncCell | } [Output]");
+    }
+}
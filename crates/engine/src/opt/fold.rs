@@ -0,0 +1,115 @@
+use crate::parser::{Node, NodeKind};
+
+/// Net effect of a cell or pointer instruction, if it is one
+pub(super) fn delta(kind: &NodeKind) -> Option<(bool, i64)> {
+    match kind {
+        NodeKind::IncCell => Some((false, 1)),
+        NodeKind::DecCell => Some((false, -1)),
+        NodeKind::Add(n) => Some((false, *n)),
+        NodeKind::IncPtr => Some((true, 1)),
+        NodeKind::DecPtr => Some((true, -1)),
+        NodeKind::Move(n) => Some((true, *n)),
+        _ => None,
+    }
+}
+
+/// Fold runs of `+`/`-` into `Add(n)` and runs of `>`/`<` into `Move(n)`.
+///
+/// A run only spans adjacent instructions of the same class, so it never crosses a loop,
+/// lock, wait, notify or parallel boundary. Runs that cancel out are removed.
+pub fn fold_runs(nodes: Vec<Node>) -> Vec<Node> {
+    let mut out: Vec<Node> = Vec::with_capacity(nodes.len());
+    // Pointer/cell class and net amount of the run ending at `out.last()`
+    let mut run: Option<(bool, i64)> = None;
+
+    for node in nodes {
+        let Some((is_move, n)) = delta(&node.kind) else {
+            let kind = match node.kind {
+                NodeKind::Loop(body) => NodeKind::Loop(fold_runs(body)),
+                NodeKind::Parallel(branches) => {
+                    NodeKind::Parallel(branches.into_iter().map(fold_runs).collect())
+                }
+                kind => kind,
+            };
+            out.push(Node {
+                kind,
+                span: node.span,
+            });
+            run = None;
+            continue;
+        };
+
+        match run {
+            Some((run_move, total)) if run_move == is_move => {
+                let total = total.wrapping_add(n);
+                if total == 0 {
+                    // The run cancelled out; the run before it (e.g. `>` in `>+-<`) may resume
+                    out.pop();
+                    run = out.last().and_then(|n| delta(&n.kind));
+                } else {
+                    out.last_mut().unwrap().kind = folded(is_move, total);
+                    run = Some((is_move, total));
+                }
+            }
+            _ => {
+                out.push(Node {
+                    kind: folded(is_move, n),
+                    span: node.span,
+                });
+                run = Some((is_move, n));
+            }
+        }
+    }
+
+    out
+}
+
+fn folded(is_move: bool, n: i64) -> NodeKind {
+    if is_move {
+        NodeKind::Move(n)
+    } else {
+        NodeKind::Add(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold_runs;
+    use crate::opt::tests::{shape, tree};
+
+    fn folded(src: &str) -> String {
+        shape(&fold_runs(tree(src)))
+    }
+
+    #[test]
+    fn folds_runs() {
+        assert_eq!(folded("+++>><--"), "Add(3) Move(1) Add(-2)");
+        assert_eq!(folded("[->>+<<]"), "[Add(-1) Move(2) Add(1) Move(-2)]");
+    }
+
+    #[test]
+    fn drops_cancelled_runs() {
+        assert_eq!(folded("+-"), "");
+        // The cell run cancels, then the pointer run around it resumes and cancels too
+        assert_eq!(folded(">+-<."), "Output");
+        assert_eq!(folded(">+-<<"), "Move(-1)");
+    }
+
+    #[test]
+    fn stops_at_sync_boundaries() {
+        assert_eq!(
+            folded("+(+)+"),
+            "Add(1) LockAcquire Add(1) LockRelease Add(1)"
+        );
+        assert_eq!(folded(">^<"), "Move(1) Wait Move(-1)");
+        assert_eq!(folded("-v+"), "Add(-1) Notify Add(1)");
+        assert_eq!(folded("+~+"), "Add(1) Sleep(1) Add(1)");
+    }
+
+    #[test]
+    fn stops_at_loops_and_parallel_blocks() {
+        assert_eq!(folded("+[+]+"), "Add(1) [Add(1)] Add(1)");
+        assert_eq!(folded(">>{<|++-}<"), "Move(2) {Move(-1) | Add(1)} Move(-1)");
+        assert_eq!(folded("{+|}-"), "{Add(1) | } Add(-1)");
+    }
+}
@@ -0,0 +1,196 @@
+use super::fold::delta;
+use crate::parser::{Node, NodeKind};
+
+// Idiom passes match folded (`Add`/`Move`) and unfolded (`+`/`>` runs) bodies alike, so they
+// work with or without `fold`, and leave other loops untouched.
+
+/// `[-]` / `[+]` becomes `Clear`
+pub fn clear_loops(nodes: Vec<Node>) -> Vec<Node> {
+    rewrite_loops(nodes, clear)
+}
+
+/// `[>>]` / `[<]` becomes `Scan(step)`
+pub fn scan_loops(nodes: Vec<Node>) -> Vec<Node> {
+    rewrite_loops(nodes, scan)
+}
+
+/// The `Clear`, `Scan` or `MulAdd` a loop with this body amounts to, if any
+pub fn lower_loop(body: &[Node]) -> Option<NodeKind> {
+    clear(body).or_else(|| scan(body)).or_else(|| mul_add(body))
+}
+
+fn clear(body: &[Node]) -> Option<NodeKind> {
+    match net(body, false) {
+        Some(1 | -1) => Some(NodeKind::Clear),
+        _ => None,
+    }
+}
+
+fn scan(body: &[Node]) -> Option<NodeKind> {
+    match net(body, true) {
+        Some(0) | None => None,
+        Some(step) => Some(NodeKind::Scan(step)),
+    }
+}
+
+/// Net amount of a body made only of pointer moves (`is_move`) or only of cell additions
+fn net(body: &[Node], is_move: bool) -> Option<i64> {
+    body.iter()
+        .try_fold(0i64, |total, node| match delta(&node.kind) {
+            Some((m, n)) if m == is_move => Some(total.wrapping_add(n)),
+            _ => None,
+        })
+}
+
+/// Balanced loops that only add to other cells and step the current cell by one, like
+/// `[->+>++<<]`, become `MulAdd` with factors relative to the current cell's value
+pub fn mul_add_loops(nodes: Vec<Node>) -> Vec<Node> {
+    rewrite_loops(nodes, mul_add)
+}
+
+/// Bottom-up: replace every loop whose body `f` recognizes
+fn rewrite_loops(nodes: Vec<Node>, f: fn(&[Node]) -> Option<NodeKind>) -> Vec<Node> {
+    nodes
+        .into_iter()
+        .map(|node| {
+            let kind = match node.kind {
+                NodeKind::Loop(body) => {
+                    let body = rewrite_loops(body, f);
+                    f(&body).unwrap_or(NodeKind::Loop(body))
+                }
+                NodeKind::Parallel(branches) => {
+                    NodeKind::Parallel(branches.into_iter().map(|b| rewrite_loops(b, f)).collect())
+                }
+                kind => kind,
+            };
+            Node {
+                kind,
+                span: node.span,
+            }
+        })
+        .collect()
+}
+
+fn mul_add(body: &[Node]) -> Option<NodeKind> {
+    // Net additions per offset, in order of first touch
+    let mut offset = 0;
+    let mut adds: Vec<(i64, i64)> = Vec::new();
+    for node in body {
+        match delta(&node.kind)? {
+            (true, n) => offset += n,
+            (false, n) => match adds.iter_mut().find(|(o, _)| *o == offset) {
+                Some((_, total)) => *total += n,
+                None => adds.push((offset, n)),
+            },
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+
+    // The loop runs `v` times when stepping by -1 and `-v` times (mod 2^bits) when stepping by +1
+    let sign = match adds.iter().find(|(o, _)| *o == 0) {
+        Some((_, -1)) => 1,
+        Some((_, 1)) => -1,
+        _ => return None,
+    };
+    let targets: Vec<(i64, i64)> = adds
+        .into_iter()
+        .filter(|&(o, f)| o != 0 && f != 0)
+        .map(|(o, f)| (o, f * sign))
+        .collect();
+    Some(NodeKind::MulAdd(targets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clear_loops, mul_add_loops, scan_loops};
+    use crate::opt::fold::fold_runs;
+    use crate::opt::tests::{shape, tree};
+    use crate::parser::Node;
+
+    /// Run `pass` over unfolded `src`, checking that folding before or after it gives the same tree
+    fn lowered(pass: fn(Vec<Node>) -> Vec<Node>, src: &str) -> String {
+        let unfolded = shape(&pass(tree(src)));
+        let folded = shape(&pass(fold_runs(tree(src))));
+        assert_eq!(shape(&fold_runs(pass(tree(src)))), folded, "{src}");
+        unfolded
+    }
+
+    #[test]
+    fn clears() {
+        assert_eq!(lowered(clear_loops, "[-]"), "Clear");
+        assert_eq!(lowered(clear_loops, "[+]"), "Clear");
+        assert_eq!(lowered(clear_loops, "[[-]>]"), "[Clear IncPtr]");
+        // Stepping by two misses zero on odd cells; a pointer move changes the cell tested
+        assert_eq!(lowered(clear_loops, "[--]"), "[DecCell DecCell]");
+        assert_eq!(lowered(clear_loops, "[->]"), "[DecCell IncPtr]");
+    }
+
+    #[test]
+    fn scans() {
+        assert_eq!(lowered(scan_loops, "[>>]"), "Scan(2)");
+        assert_eq!(lowered(scan_loops, "[<]"), "Scan(-1)");
+        assert_eq!(lowered(scan_loops, "[>+]"), "[IncPtr IncCell]");
+    }
+
+    #[test]
+    fn scan_needs_a_net_move() {
+        assert_eq!(shape(&scan_loops(tree("[><]"))), "[IncPtr DecPtr]");
+        assert_eq!(shape(&scan_loops(tree("[]"))), "[]");
+    }
+
+    #[test]
+    fn mul_adds() {
+        assert_eq!(
+            lowered(mul_add_loops, "[->+>++<<]"),
+            "MulAdd([(1, 1), (2, 2)])"
+        );
+        // Counting up runs the loop `-v` times, so factors flip sign
+        assert_eq!(lowered(mul_add_loops, "[<-->+]"), "MulAdd([(-1, 2)])");
+        // Targets that cancel out are dropped
+        assert_eq!(lowered(mul_add_loops, "[>+-<-]"), "MulAdd([])");
+    }
+
+    #[test]
+    fn mul_add_rejects_unbalanced_pointer() {
+        assert_eq!(
+            lowered(mul_add_loops, "[->+<<]"),
+            "[DecCell IncPtr IncCell DecPtr DecPtr]"
+        );
+    }
+
+    #[test]
+    fn mul_add_rejects_counter_not_stepping_by_one() {
+        assert_eq!(
+            lowered(mul_add_loops, "[-->+<]"),
+            "[DecCell DecCell IncPtr IncCell DecPtr]"
+        );
+        // A counter that never changes would loop forever on a nonzero cell
+        assert_eq!(lowered(mul_add_loops, "[>+<]"), "[IncPtr IncCell DecPtr]");
+        assert_eq!(
+            lowered(mul_add_loops, "[-+>+<]"),
+            "[DecCell IncCell IncPtr IncCell DecPtr]"
+        );
+    }
+
+    #[test]
+    fn mul_add_rejects_other_instructions() {
+        assert_eq!(
+            lowered(mul_add_loops, "[->.<]"),
+            "[DecCell IncPtr Output DecPtr]"
+        );
+        assert_eq!(
+            lowered(mul_add_loops, "[-(>+<)]"),
+            "[DecCell LockAcquire IncPtr IncCell DecPtr LockRelease]"
+        );
+    }
+
+    #[test]
+    fn lowers_inside_parallel_branches() {
+        assert_eq!(
+            shape(&clear_loops(tree("{[-]|[[+]]}"))),
+            "{Clear | [Clear]}"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt::Write as _;
+
+use crate::parser::{Node, NodeKind};
+
+mod dead;
+mod fold;
+mod idiom;
+
+pub use idiom::lower_loop;
+
+pub struct Pass {
+    pub name: &'static str,
+    pub level: u8, // Lowest -O level that enables the pass
+    run: fn(Vec<Node>) -> Vec<Node>,
+}
+
+/// All passes, in the order they run
+pub const PASSES: &[Pass] = &[
+    Pass {
+        name: "fold",
+        level: 1,
+        run: fold::fold_runs,
+    },
+    Pass {
+        name: "clear",
+        level: 2,
+        run: idiom::clear_loops,
+    },
+    Pass {
+        name: "scan",
+        level: 2,
+        run: idiom::scan_loops,
+    },
+    Pass {
+        name: "mul-add",
+        level: 2,
+        run: idiom::mul_add_loops,
+    },
+    Pass {
+        name: "dead-loop",
+        level: 3,
+        run: dead::dead_loops,
+    },
+];
+
+pub const MAX_LEVEL: u8 = 3;
+
+/// Runs the enabled passes over the parsed tree. At -O0 nothing runs and every instruction is
+/// lowered one-to-one.
+pub struct PassManager {
+    enabled: Vec<bool>, // Indexed like `PASSES`
+    pub print_after_each: bool,
+}
+
+impl PassManager {
+    pub fn new(level: u8) -> Self {
+        Self {
+            enabled: PASSES.iter().map(|p| p.level <= level).collect(),
+            print_after_each: false,
+        }
+    }
+
+    pub fn set_enabled(&mut self, name: &str, on: bool) -> Result<(), String> {
+        let idx = PASSES
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| format!("unknown pass: {name}"))?;
+        self.enabled[idx] = on;
+        Ok(())
+    }
+
+    pub fn run(&self, mut nodes: Vec<Node>) -> Vec<Node> {
+        for (pass, _) in PASSES.iter().zip(&self.enabled).filter(|(_, on)| **on) {
+            nodes = (pass.run)(nodes);
+            if self.print_after_each {
+                eprintln!("; after {}", pass.name);
+                eprint!("{}", dump(&nodes));
+            }
+        }
+        nodes
+    }
+}
+
+/// Render the tree one node per line, indented by nesting, with source positions
+pub fn dump(nodes: &[Node]) -> String {
+    let mut out = String::new();
+    dump_into(&mut out, nodes, 0);
+    out
+}
+
+fn dump_into(out: &mut String, nodes: &[Node], depth: usize) {
+    for node in nodes {
+        let pad = "  ".repeat(depth);
+        match &node.kind {
+            NodeKind::Loop(body) => {
+                let _ = writeln!(out, "{pad}Loop @{}", node.span);
+                dump_into(out, body, depth + 1);
+            }
+            NodeKind::Parallel(branches) => {
+                let _ = writeln!(out, "{pad}Parallel @{}", node.span);
+                for (i, branch) in branches.iter().enumerate() {
+                    let _ = writeln!(out, "{pad}  Branch {i}");
+                    dump_into(out, branch, depth + 2);
+                }
+            }
+            kind => {
+                let _ = writeln!(out, "{pad}{kind:?} @{}", node.span);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fmt::Write as _;
+
+    use crate::lexer::{self, Dialect};
+    use crate::parser::{self, Node, NodeKind};
+
+    /// Parse Brainfork source that is known to be well formed
+    pub fn tree(src: &str) -> Vec<Node> {
+        let (tokens, _) = lexer::lex(src, Dialect::Brainfork).unwrap();
+        parser::parse(&tokens).unwrap()
+    }
+
+    /// Render a tree without spans: loops as `[..]`, parallel blocks as `{.. | ..}`
+    pub fn shape(nodes: &[Node]) -> String {
+        let mut out = String::new();
+        for node in nodes {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            match &node.kind {
+                NodeKind::Loop(body) => {
+                    let _ = write!(out, "[{}]", shape(body));
+                }
+                NodeKind::Parallel(branches) => {
+                    let branches: Vec<String> = branches.iter().map(|b| shape(b)).collect();
+                    let _ = write!(out, "{{{}}}", branches.join(" | "));
+                }
+                kind => {
+                    let _ = write!(out, "{kind:?}");
+                }
+            }
+        }
+        out
+    }
+}
@@ -1,9 +1,17 @@
-use crate::lexer::Token;
+use crate::diagnostics::Diagnostic;
+use crate::lexer::{Span, Token, TokenKind};
+use std::fmt;
 use std::iter::Peekable;
 use std::slice::Iter;
 
 #[derive(Debug, Clone)]
-pub enum Node {
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum NodeKind {
     IncPtr,
     DecPtr,
     IncCell,
@@ -17,64 +25,247 @@ pub enum Node {
     Sleep(usize),
     Wait,
     Notify,
+    // Produced by the optimizer, never by the parser
+    Add(i64),  // Add to the current cell (wrapping)
+    Move(i64), // Move the pointer by a signed distance
+    Clear,     // Set the current cell to zero
+    Scan(i64), // Move by the given step until the current cell is zero
+    // For each (offset, factor) add `factor * current` to the cell at `offset`, then clear.
+    // Does nothing on a zero cell, as the loop it replaces never ran: the targets are
+    // neither touched nor bounds checked
+    MulAdd(Vec<(i64, i64)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnclosedLoop,
+    UnclosedParallel,
+    UnmatchedLoopEnd,
+    StrayParSep,
+    StrayParEnd,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
 }
 
-fn parse_parallel(iter: &mut Peekable<Iter<Token>>) -> Vec<Vec<Node>> {
-    let mut branches = Vec::new();
-    loop {
-        let branch = parse_nodes(iter, &[Token::ParSep, Token::ParEnd]);
-        branches.push(branch);
-        match iter.next() {
-            Some(Token::ParSep) => continue,
-            Some(Token::ParEnd) => break,
-            other => panic!("Expected '|' or '}}', found {other:?}"),
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::UnclosedLoop => write!(f, "unclosed '[': missing matching ']'"),
+            ParseErrorKind::UnclosedParallel => write!(f, "unclosed '{{': missing matching '}}'"),
+            ParseErrorKind::UnmatchedLoopEnd => write!(f, "unmatched ']'"),
+            ParseErrorKind::StrayParSep => write!(f, "'|' outside of a parallel block"),
+            ParseErrorKind::StrayParEnd => write!(f, "unmatched '}}'"),
         }
     }
-    branches
 }
 
-fn parse_nodes(iter: &mut Peekable<Iter<Token>>, terminators: &[Token]) -> Vec<Node> {
-    let mut nodes = Vec::new();
-    while let Some(&token_ref) = iter.peek() {
-        let token = token_ref;
-        if terminators.contains(token) {
-            break;
+impl From<&ParseError> for Diagnostic {
+    fn from(err: &ParseError) -> Self {
+        Diagnostic::error(err.span, err.to_string())
+    }
+}
+
+/// Error reported for a closing token when no open construct accepts it
+fn stray_kind(kind: &TokenKind) -> Option<ParseErrorKind> {
+    match kind {
+        TokenKind::LoopEnd => Some(ParseErrorKind::UnmatchedLoopEnd),
+        TokenKind::ParSep => Some(ParseErrorKind::StrayParSep),
+        TokenKind::ParEnd => Some(ParseErrorKind::StrayParEnd),
+        _ => None,
+    }
+}
+
+/// Constructs currently open around the parse position, innermost last
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Open {
+    Loop,
+    Branch,
+}
+
+impl Open {
+    fn accepts(self, kind: &TokenKind) -> bool {
+        match self {
+            Open::Loop => *kind == TokenKind::LoopEnd,
+            Open::Branch => matches!(kind, TokenKind::ParSep | TokenKind::ParEnd),
         }
-        match iter.next().unwrap() {
-            Token::IncPtr => nodes.push(Node::IncPtr),
-            Token::DecPtr => nodes.push(Node::DecPtr),
-            Token::IncCell => nodes.push(Node::IncCell),
-            Token::DecCell => nodes.push(Node::DecCell),
-            Token::Output => nodes.push(Node::Output),
-            Token::Input => nodes.push(Node::Input),
-            Token::LoopStart => {
-                let body = parse_nodes(iter, &[Token::LoopEnd]);
-                iter.next();
-                nodes.push(Node::Loop(body));
+    }
+}
+
+struct Parser<'a> {
+    iter: Peekable<Iter<'a, Token>>,
+    open: Vec<Open>,
+    errors: Vec<ParseError>,
+}
+
+impl Parser<'_> {
+    fn error(&mut self, kind: ParseErrorKind, span: Span) {
+        self.errors.push(ParseError { kind, span });
+    }
+
+    fn peek_kind(&mut self) -> Option<&TokenKind> {
+        self.iter.peek().map(|t| &t.kind)
+    }
+
+    /// Parse a body until EOF or a closer that belongs to one of the open constructs.
+    /// Closers nothing is waiting for are reported and skipped.
+    fn parse_nodes(&mut self) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        while let Some(&token) = self.iter.peek() {
+            let span = token.span;
+            if let Some(err) = stray_kind(&token.kind) {
+                if self.open.iter().any(|o| o.accepts(&token.kind)) {
+                    break;
+                }
+                self.error(err, span);
+                self.iter.next();
+                continue;
             }
-            Token::ParStart => {
-                let branches = parse_parallel(iter);
-                nodes.push(Node::Parallel(branches));
+            self.iter.next();
+            let kind = match &token.kind {
+                TokenKind::IncPtr => NodeKind::IncPtr,
+                TokenKind::DecPtr => NodeKind::DecPtr,
+                TokenKind::IncCell => NodeKind::IncCell,
+                TokenKind::DecCell => NodeKind::DecCell,
+                TokenKind::Output => NodeKind::Output,
+                TokenKind::Input => NodeKind::Input,
+                TokenKind::LoopStart => NodeKind::Loop(self.parse_loop(span)),
+                TokenKind::ParStart => NodeKind::Parallel(self.parse_parallel(span)),
+                TokenKind::LockStart => NodeKind::LockAcquire,
+                TokenKind::LockEnd => NodeKind::LockRelease,
+                TokenKind::Sleep => {
+                    let mut count = 1;
+                    while let Some(TokenKind::Sleep) = self.peek_kind() {
+                        count += 1;
+                        self.iter.next();
+                    }
+                    NodeKind::Sleep(count)
+                }
+                TokenKind::Wait => NodeKind::Wait,
+                TokenKind::Notify => NodeKind::Notify,
+                TokenKind::LoopEnd | TokenKind::ParSep | TokenKind::ParEnd => unreachable!(),
+            };
+            nodes.push(Node { kind, span });
+        }
+        nodes
+    }
+
+    fn parse_loop(&mut self, start: Span) -> Vec<Node> {
+        self.open.push(Open::Loop);
+        let body = self.parse_nodes();
+        self.open.pop();
+        match self.peek_kind() {
+            Some(TokenKind::LoopEnd) => {
+                self.iter.next();
             }
-            Token::LockStart => nodes.push(Node::LockAcquire),
-            Token::LockEnd => nodes.push(Node::LockRelease),
-            Token::Sleep => {
-                let mut count = 1;
-                while let Some(&&Token::Sleep) = iter.peek() {
-                    count += 1;
-                    iter.next();
+            // EOF, or a closer of an enclosing parallel block
+            _ => self.error(ParseErrorKind::UnclosedLoop, start),
+        }
+        body
+    }
+
+    fn parse_parallel(&mut self, start: Span) -> Vec<Vec<Node>> {
+        let mut branches = Vec::new();
+        loop {
+            self.open.push(Open::Branch);
+            branches.push(self.parse_nodes());
+            self.open.pop();
+            match self.peek_kind() {
+                Some(TokenKind::ParSep) => {
+                    self.iter.next();
+                }
+                Some(TokenKind::ParEnd) => {
+                    self.iter.next();
+                    break;
+                }
+                // EOF, or a ']' of an enclosing loop
+                _ => {
+                    self.error(ParseErrorKind::UnclosedParallel, start);
+                    break;
                 }
-                nodes.push(Node::Sleep(count));
             }
-            Token::Wait => nodes.push(Node::Wait),
-            Token::Notify => nodes.push(Node::Notify),
-            _ => break,
         }
+        branches
+    }
+}
+
+/// Parse the whole token stream, reporting every syntax error in source order
+pub fn parse(tokens: &[Token]) -> Result<Vec<Node>, Vec<ParseError>> {
+    let mut parser = Parser {
+        iter: tokens.iter().peekable(),
+        open: Vec::new(),
+        errors: Vec::new(),
+    };
+    let nodes = parser.parse_nodes();
+    if parser.errors.is_empty() {
+        Ok(nodes)
+    } else {
+        parser.errors.sort_by_key(|e| e.span);
+        Err(parser.errors)
     }
-    nodes
 }
 
-pub fn parse(tokens: &[Token]) -> Vec<Node> {
-    let mut iter = tokens.iter().peekable();
-    parse_nodes(&mut iter, &[])
+#[cfg(test)]
+mod tests {
+    use super::ParseErrorKind::*;
+    use super::{ParseErrorKind, parse};
+    use crate::lexer::{Dialect, lex};
+
+    /// Kinds and `(line, col)` of every error reported for `src`
+    fn errors(src: &str) -> Vec<(ParseErrorKind, (usize, usize))> {
+        let (tokens, _) = lex(src, Dialect::Brainfork).unwrap();
+        parse(&tokens)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| (e.kind, (e.span.line, e.span.col)))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_nested_blocks() {
+        let (tokens, _) = lex("+[>{[-]|(.)|}<]{|}", Dialect::Brainfork).unwrap();
+        assert!(parse(&tokens).is_ok());
+    }
+
+    #[test]
+    fn reports_stray_closers() {
+        assert_eq!(errors("+]"), [(UnmatchedLoopEnd, (1, 2))]);
+        assert_eq!(errors("|"), [(StrayParSep, (1, 1))]);
+        assert_eq!(errors("}"), [(StrayParEnd, (1, 1))]);
+        // A loop only accepts ']', so a '|' or '}' inside one is stray too
+        assert_eq!(errors("[|]"), [(StrayParSep, (1, 2))]);
+        assert_eq!(errors("[}]"), [(StrayParEnd, (1, 2))]);
+    }
+
+    #[test]
+    fn reports_unclosed_openers() {
+        assert_eq!(errors("+["), [(UnclosedLoop, (1, 2))]);
+        assert_eq!(errors("{+|"), [(UnclosedParallel, (1, 1))]);
+        // The enclosing block's closer ends the inner one, which is reported instead
+        assert_eq!(errors("[{]"), [(UnclosedParallel, (1, 2))]);
+        assert_eq!(errors("{[}"), [(UnclosedLoop, (1, 2))]);
+    }
+
+    #[test]
+    fn reports_every_error_in_source_order() {
+        assert_eq!(
+            errors("[\n+]|}[\n]]"),
+            [
+                (StrayParSep, (2, 3)),
+                (StrayParEnd, (2, 4)),
+                (UnmatchedLoopEnd, (3, 2)),
+            ]
+        );
+        assert_eq!(
+            errors("]{\n["),
+            [
+                (UnmatchedLoopEnd, (1, 1)),
+                (UnclosedParallel, (1, 2)),
+                (UnclosedLoop, (2, 1)),
+            ]
+        );
+    }
 }
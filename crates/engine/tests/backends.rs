@@ -0,0 +1,245 @@
+//! Runs programs through the interpreter and every compiled backend and compares the output.
+//! The compiled backends need the same toolchain as `engine build`: clang or llc, cc and as,
+//! honouring `$CLANG`, `$LLC`, `$CC` and `$AS`. Backends whose tools are missing are skipped.
+//! The JIT and the assembly backend are only exercised on x86-64.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{self, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ENGINE: &str = env!("CARGO_BIN_EXE_engine");
+
+/// How long any one child may run before the test fails instead of hanging
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Whether `$var`, or `default` when unset, runs
+fn has_tool(var: &str, default: &str) -> bool {
+    let tool = env::var_os(var).unwrap_or_else(|| OsString::from(default));
+    Command::new(tool)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// `--emit` targets that run on this machine and whose toolchain is installed
+fn emits() -> Vec<&'static str> {
+    let cc = has_tool("CC", "cc");
+    let mut emits = Vec::new();
+    for (emit, available) in [
+        (
+            "ir",
+            has_tool("CLANG", "clang") || has_tool("LLC", "llc") && cc,
+        ),
+        ("c", cc),
+        (
+            "asm",
+            cfg!(target_arch = "x86_64") && has_tool("AS", "as") && cc,
+        ),
+    ] {
+        if available {
+            emits.push(emit);
+        } else {
+            eprintln!("skipping --emit={emit}: toolchain not found");
+        }
+    }
+    emits
+}
+
+/// Run `cmd` on `input` to completion, failing the test if it outlives `TIMEOUT`. The inputs
+/// and outputs here are small enough to sit in the pipes until the child exits.
+fn output(cmd: &mut Command, input: &[u8]) -> Output {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let start = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if start.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            panic!("{cmd:?} did not finish within {TIMEOUT:?}");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    child.wait_with_output().unwrap()
+}
+
+/// Write `src` to a scratch file named after the test
+fn source(name: &str, src: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("engine-{}-{name}.bf", process::id()));
+    fs::write(&path, src).unwrap();
+    path
+}
+
+/// Stdout of `engine interpret` on `input`, asserting it succeeded
+fn interpret(path: &PathBuf, flags: &[&str], input: &[u8]) -> Vec<u8> {
+    let out = output(
+        Command::new(ENGINE).arg("interpret").arg(path).args(flags),
+        input,
+    );
+    assert!(
+        out.status.success(),
+        "interpret {flags:?}: {}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    out.stdout
+}
+
+/// Stdout of the executable `engine build --emit=<emit>` produces on `input`, asserting both
+/// succeeded
+fn compiled(path: &PathBuf, emit: &str, flags: &[&str], input: &[u8]) -> Vec<u8> {
+    let exe = path.with_extension(emit);
+    let out = output(
+        Command::new(ENGINE)
+            .arg("build")
+            .arg(path)
+            .arg("-o")
+            .arg(&exe)
+            .arg(format!("--emit={emit}"))
+            .args(flags),
+        b"",
+    );
+    assert!(
+        out.status.success(),
+        "build --emit={emit} {flags:?}: {}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let run = output(&mut Command::new(&exe), input);
+    fs::remove_file(&exe).unwrap();
+    assert!(
+        run.status.success(),
+        "--emit={emit} {flags:?}: {}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    run.stdout
+}
+
+/// Every optimization level, and the idiom passes without `fold`, print the same as -O0
+#[test]
+fn optimization_levels_agree() {
+    // Clears, scans, mul-adds with both counter directions and a dead loop after each
+    let path = source(
+        "opt-levels",
+        "[.]++++++++[>++++++<-]>[>+>++<<-][-]>[>+<-]<<[<]>>>.>-[<+>+]<.[-][.]",
+    );
+    let expected = interpret(&path, &["-O0"], b"");
+    assert_eq!(expected, [144, 145]);
+    for flags in [
+        &["-O1"][..],
+        &["-O2"],
+        &["-O3"],
+        &["-O3", "--no-pass=fold"],
+        &["-O0", "--pass=mul-add"],
+    ] {
+        assert_eq!(interpret(&path, flags, b""), expected, "{flags:?}");
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+/// At -O0 a scan or mul-add loop that runs off the tape still fails at the step that left it
+#[test]
+fn loop_shortcuts_fail_at_the_step() {
+    for (name, src, at) in [
+        ("scan-off", "+>+>+>+<<<[>]", "1:12"),
+        ("mul-add-off", ">>>+<<<>>>[<<<<+>>>>-]", "1:15"),
+    ] {
+        let path = source(name, src);
+        let out = output(
+            Command::new(ENGINE)
+                .arg("interpret")
+                .arg(&path)
+                .args(["-O0", "--tape-size", "4"]),
+            b"",
+        );
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(!out.status.success(), "{name}");
+        assert!(stderr.contains(&format!("at {at}")), "{name}: {stderr}");
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+/// A mul-add loop on a zero cell never runs, so its targets left of the tape are never touched
+#[test]
+fn mul_add_at_cell_zero() {
+    let path = source("mul-add-zero", "[<+>-]++++++++[>++++++<-]>.");
+    for opt in ["-O0", "-O2"] {
+        assert_eq!(interpret(&path, &[opt], b""), b"0", "interpret {opt}");
+    }
+    if cfg!(target_arch = "x86_64") {
+        assert_eq!(interpret(&path, &["--jit"], b""), b"0", "jit");
+    }
+    for emit in emits() {
+        assert_eq!(
+            compiled(&path, emit, &["--bounds-check"], b""),
+            b"0",
+            "{emit}"
+        );
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+/// Branches start at the parent's cell, so `^` and `v` there meet on the same cell's signal.
+/// A notify is lost if nobody waits yet, so the notifier repeats it until the waiter clears
+/// the flag two cells right, however the threads are scheduled.
+#[test]
+fn wait_notify_matches_compiled() {
+    let src = format!(
+        ">++++++++[>+++++++<-]> >>+<< {{ ^ >>-<< . | >{}.< >>[<<v>>]<< }}",
+        "+".repeat(66)
+    );
+    let path = source("wait-notify", &src);
+    let expected = interpret(&path, &[], b"");
+    assert_eq!(expected, b"B8");
+    if cfg!(target_arch = "x86_64") {
+        assert_eq!(interpret(&path, &["--jit"], b""), expected, "jit");
+    }
+    for emit in emits() {
+        assert_eq!(compiled(&path, emit, &[], b""), expected, "{emit}");
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+/// `,` fills a 64-bit cell with exactly the byte read, whatever the EOF mode
+#[test]
+fn input_fills_wide_cells() {
+    // Prints 0 when the cell held 'A' and nothing above it
+    let path = source(
+        "wide-input",
+        &format!(",{}[[-]>+<]>{}.", "-".repeat(65), "+".repeat(48)),
+    );
+    for eof in ["--eof=zero", "--eof=unchanged", "--eof=minus-one"] {
+        let flags = ["--cell-bits=64", eof];
+        assert_eq!(interpret(&path, &flags, b"A"), b"0", "interpret {eof}");
+        for emit in emits() {
+            assert_eq!(compiled(&path, emit, &flags, b"A"), b"0", "{emit} {eof}");
+        }
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+/// Locks and signals at the far end of a large tape, whose sync slots the assembly backend
+/// keeps in zeroed `.bss` and the IR backend in chunks allocated on first touch
+#[test]
+fn sync_at_the_end_of_a_large_tape() {
+    let src = format!(
+        "<<<<< >++++++++[>+++++++<-]> >>+<< {{ ^ >>-<< (.) | (>{}.<) >>[<<v>>]<< }}",
+        "+".repeat(66)
+    );
+    let path = source("far-sync", &src);
+    let flags = ["--tape-wrap", "--tape-size", "1000000"];
+    let expected = interpret(&path, &flags, b"");
+    assert_eq!(expected, b"B8");
+    for emit in emits() {
+        assert_eq!(compiled(&path, emit, &flags, b""), expected, "{emit}");
+    }
+    fs::remove_file(&path).unwrap();
+}
@@ -6,14 +6,41 @@ mod vector_clock;
 type Tid = u64;
 type Cell = i64;
 
-const TAPE_LEN: usize = 30000;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tape length of the instrumented program, set by `tsan_init` before any thread starts. 0
+/// means the tape grows on demand, so any cell is on it.
+static TAPE_LEN: AtomicUsize = AtomicUsize::new(30000);
+
+fn tape_len() -> usize {
+    TAPE_LEN.load(Ordering::Relaxed)
+}
+
+/// Whether `idx` is a cell the shadow state covers. Outside the tape (only reachable without
+/// `--bounds-check`) the access is reported instead of being tracked.
+fn on_tape(idx: Cell) -> bool {
+    let len = tape_len();
+    let inside = len == 0 || usize::try_from(idx).is_ok_and(|i| i < len);
+    if !inside {
+        eprintln!(
+            "[TSan] access outside the {}-cell tape: cell {idx}",
+            tape_len()
+        );
+    }
+    inside
+}
+
+/// `on_tape` for the current cell of `s`
+unsafe fn state_on_tape(s: *const State) -> bool {
+    let s = unsafe { s.as_ref().expect("State pointer is null") };
+    on_tape(s.ptr_index)
+}
 
 #[repr(C)]
 #[derive(Debug)]
 pub struct State {
     tape_base: *mut i8,
     ptr_index: Cell,
-    mutex_slab: *mut i8,
     lock_stack: *mut i64,
     lock_sp: i64,
     lock_cap: i64,
@@ -43,8 +70,16 @@ fn merge_race(r1: Race, r2: Race) -> Race {
     }
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn tsan_init(tape_len: i64) {
+    TAPE_LEN.store(tape_len as usize, Ordering::Relaxed);
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn tsan_write(s: *const State) {
+    if !unsafe { state_on_tape(s) } {
+        return;
+    }
     let res1 = unsafe { lockset::lockset_check(s, true) };
     let res2 = vector_clock::vector_clock_write(s);
 
@@ -56,6 +91,9 @@ pub unsafe extern "C" fn tsan_write(s: *const State) {
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn tsan_read(s: *const State) {
+    if !unsafe { state_on_tape(s) } {
+        return;
+    }
     let res1 = unsafe { lockset::lockset_check(s, false) };
     let res2 = vector_clock::vector_clock_read(s);
 
@@ -67,11 +105,17 @@ pub unsafe extern "C" fn tsan_read(s: *const State) {
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn tsan_acquire(s: *const State, idx: Cell) {
+    if !on_tape(idx) {
+        return;
+    }
     vector_clock::vector_clock_acquire(s, idx);
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn tsan_release(s: *const State, idx: Cell) {
+    if !on_tape(idx) {
+        return;
+    }
     vector_clock::vector_clock_release(s, idx);
 }
 
@@ -89,15 +133,24 @@ pub unsafe extern "C" fn tsan_join(child_tid: Tid) {
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn tsan_pre_wait(s: *const State) {
+    if !unsafe { state_on_tape(s) } {
+        return;
+    }
     vector_clock::vector_clock_pre_wait(s);
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn tsan_post_wait(s: *const State) {
+    if !unsafe { state_on_tape(s) } {
+        return;
+    }
     vector_clock::vector_clock_post_wait(s);
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn tsan_notify(s: *const State) {
+    if !unsafe { state_on_tape(s) } {
+        return;
+    }
     vector_clock::vector_clock_notify(s);
 }
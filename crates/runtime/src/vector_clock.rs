@@ -3,7 +3,7 @@ use std::{
     sync::{LazyLock, Mutex},
 };
 
-use crate::{Cell, Race, State, TAPE_LEN, Tid};
+use crate::{Cell, Race, State, Tid};
 
 type VectorClock = HashMap<Tid, u64>;
 
@@ -29,6 +29,19 @@ fn join_in(a: &mut VectorClock, b: &VectorClock) {
     }
 }
 
+/// One clock per tape cell, created on first use. Only touched cells are stored, so a growable
+/// tape used far from cell 0 costs no more than one used near it.
+#[derive(Default)]
+struct Shadow {
+    clocks: HashMap<Cell, VectorClock>,
+}
+
+impl Shadow {
+    fn at(&mut self, x: Cell) -> &mut VectorClock {
+        self.clocks.entry(x).or_default()
+    }
+}
+
 #[derive(Clone)]
 struct WaitMark {
     id: Cell,
@@ -38,25 +51,14 @@ struct WaitMark {
 #[derive(Default)]
 pub struct RaceDetector {
     ct: HashMap<Tid, VectorClock>,
-    rx: Vec<VectorClock>,
-    wx: Vec<VectorClock>,
-    lm: Vec<VectorClock>,
-    nclock: Vec<VectorClock>,
+    rx: Shadow,
+    wx: Shadow,
+    lm: Shadow,
+    nclock: Shadow,
     wait_seen: HashMap<Tid, WaitMark>,
 }
 
 impl RaceDetector {
-    pub fn new() -> Self {
-        Self {
-            ct: HashMap::new(),
-            rx: vec![HashMap::new(); TAPE_LEN],
-            wx: vec![HashMap::new(); TAPE_LEN],
-            lm: vec![HashMap::new(); TAPE_LEN],
-            nclock: vec![HashMap::new(); TAPE_LEN],
-            wait_seen: HashMap::new(),
-        }
-    }
-
     fn ct_mut(&mut self, t: Tid) -> &mut VectorClock {
         self.ct.entry(t).or_default()
     }
@@ -64,44 +66,44 @@ impl RaceDetector {
     pub fn rel(&mut self, t: Tid, m: Cell) {
         let ct = self.ct_mut(t);
         tick(ct, t);
-        self.lm[m as usize] = ct.clone();
+        *self.lm.at(m) = ct.clone();
     }
 
     pub fn acq(&mut self, t: Tid, m: Cell) {
-        let lm = self.lm[m as usize].clone();
+        let lm = self.lm.at(m).clone();
         let ct = self.ct_mut(t);
         join_in(ct, &lm);
     }
 
     pub fn rd(&mut self, t: Tid, x: Cell) -> Result<(), Race> {
         let ct_snapshot = self.ct_mut(t).clone();
-        if !leq(&self.wx[x as usize], &ct_snapshot) {
+        if !leq(self.wx.at(x), &ct_snapshot) {
             return Err(Race {
                 cell: x,
                 is_write: true,
             });
         }
         let my_time = *ct_snapshot.get(&t).unwrap_or(&0);
-        self.rx[x as usize].insert(t, my_time);
+        self.rx.at(x).insert(t, my_time);
         Ok(())
     }
 
     pub fn wr(&mut self, t: Tid, x: Cell) -> Result<(), Race> {
         let ct_snapshot = self.ct_mut(t).clone();
-        if !leq(&self.wx[x as usize], &ct_snapshot) {
+        if !leq(self.wx.at(x), &ct_snapshot) {
             return Err(Race {
                 cell: x,
                 is_write: true,
             });
         }
-        if !leq(&self.rx[x as usize], &ct_snapshot) {
+        if !leq(self.rx.at(x), &ct_snapshot) {
             return Err(Race {
                 cell: x,
                 is_write: false,
             });
         }
         let my_time = *ct_snapshot.get(&t).unwrap_or(&0);
-        self.wx[x as usize].insert(t, my_time);
+        self.wx.at(x).insert(t, my_time);
         Ok(())
     }
 
@@ -125,7 +127,7 @@ impl RaceDetector {
 
     pub fn pre_wait(&mut self, t: Tid, id: Cell) {
         tick(self.ct_mut(t), t);
-        let seen = self.nclock[id as usize].clone();
+        let seen = self.nclock.at(id).clone();
         self.wait_seen.insert(t, WaitMark { id, seen });
     }
 
@@ -133,7 +135,7 @@ impl RaceDetector {
         if let Some(wm) = self.wait_seen.remove(&t)
             && wm.id == id
         {
-            let now = self.nclock[id as usize].clone();
+            let now = self.nclock.at(id).clone();
             if !leq(&now, &wm.seen) {
                 let ct = self.ct_mut(t);
                 join_in(ct, &now);
@@ -146,12 +148,12 @@ impl RaceDetector {
         let ct = self.ct_mut(t);
         tick(ct, t);
         let snapshot = ct.clone();
-        join_in(&mut self.nclock[id as usize], &snapshot);
+        join_in(self.nclock.at(id), &snapshot);
     }
 }
 
 static VECTOR_CLOCK: LazyLock<Mutex<RaceDetector>> =
-    LazyLock::new(|| Mutex::new(RaceDetector::new()));
+    LazyLock::new(|| Mutex::new(RaceDetector::default()));
 
 pub fn vector_clock_write(s: *const State) -> Result<(), Race> {
     let mut vector_clock = VECTOR_CLOCK.lock().unwrap();